@@ -0,0 +1,103 @@
+#![feature(test)]
+extern crate test;
+
+use rback::input_queue::InputQueue;
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+use test::{black_box, Bencher};
+
+/// Counts allocations so the benchmarks can check the queue does not
+/// allocate once it is running
+struct CountingAlloc;
+
+thread_local! {
+    /// Per thread so benchmarks run in parallel as tests and the test
+    /// harness itself don't add to each other's counts
+    static ALLOCATIONS: Cell<usize> = Cell::new(0);
+}
+
+/// Allocations made by this thread so far
+fn allocations() -> usize {
+    ALLOCATIONS.with(|allocations| allocations.get())
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // the thread local is gone while a thread shuts down
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Frames kept before they are confirmed and discarded, like a session with
+/// a few frames of rollback
+const UNCONFIRMED_FRAMES: u32 = 8;
+
+/// One frame of a running match, the input for the frame is added, read
+/// back and the frame `unconfirmed_frames` ago is confirmed
+fn run_frame(queue: &mut InputQueue<u32>, frame: u32, unconfirmed_frames: u32) {
+    queue.add_input((frame, frame).into()).unwrap();
+    black_box(queue.get_input(frame).unwrap());
+    if frame >= unconfirmed_frames {
+        let confirmed = frame - unconfirmed_frames;
+        black_box(queue.get_confirmed_input(confirmed).unwrap());
+        queue.discard_confirmed_frames(confirmed);
+    }
+}
+
+/// Benchmark running frames and check no allocations happen once the queue
+/// is warmed up
+fn bench_frames(b: &mut Bencher, unconfirmed_frames: u32) {
+    let mut queue = InputQueue::new();
+    let mut frame = 0;
+    b.iter(|| {
+        run_frame(&mut queue, frame, unconfirmed_frames);
+        frame += 1;
+    });
+
+    let allocations = allocations();
+    for _ in 0..1000 {
+        run_frame(&mut queue, frame, unconfirmed_frames);
+        frame += 1;
+    }
+    assert_eq!(self::allocations(), allocations);
+}
+
+#[bench]
+fn bench_confirmed_frames(b: &mut Bencher) {
+    bench_frames(b, UNCONFIRMED_FRAMES);
+}
+
+/// Lookups should take the same time no matter how many frames are waiting
+/// to be confirmed
+#[bench]
+fn bench_confirmed_frames_long_window(b: &mut Bencher) {
+    bench_frames(b, 120);
+}
+
+#[bench]
+fn bench_predicted_frames(b: &mut Bencher) {
+    let mut queue: InputQueue<u32> = InputQueue::new();
+    queue.add_input((1, 0).into()).unwrap();
+    let mut frame = 1;
+    // keep predicting ahead without ever getting the inputs
+    b.iter(|| {
+        black_box(queue.get_input(frame).unwrap());
+        frame = frame % UNCONFIRMED_FRAMES + 1;
+    });
+
+    let allocations = allocations();
+    for frame in 1..=UNCONFIRMED_FRAMES {
+        black_box(queue.get_input(frame).unwrap());
+    }
+    assert_eq!(self::allocations(), allocations);
+}
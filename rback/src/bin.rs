@@ -1,7 +1,10 @@
-use rback::network::{message::NetworkMessage, udp::NetworkHandler};
+use rback::{
+    error::NetworkError,
+    network::{message::NetworkMessage, udp::NetworkHandler},
+};
 use std::net::SocketAddr;
 
-fn main() {
+fn main() -> Result<(), NetworkError> {
     const SERVER_ADDR: &str = "127.0.0.1:12345";
     const REMOTE_ADDR: &str = "127.0.0.1:12346";
 
@@ -13,9 +16,10 @@ fn main() {
         SERVER_ADDR.parse().unwrap()
     }
 
-    let mut local = NetworkHandler::new(server_address(), remote_address());
-    let mut remote = NetworkHandler::new(remote_address(), server_address());
-    let payload = NetworkMessage::make_input("hello");
-    local.send_msg_now(&payload).unwrap();
-    remote.get_messages();
+    let mut local = NetworkHandler::new(server_address(), remote_address())?;
+    let mut remote = NetworkHandler::new(remote_address(), server_address())?;
+    let payload = NetworkMessage::<String>::KeepAlive;
+    local.send_msg_now(&payload)?;
+    remote.get_messages::<String>();
+    Ok(())
 }
@@ -1,7 +1,9 @@
-use crate::FrameSize;
+use crate::{FrameSize, PlayerHandle};
+use laminar::ErrorKind;
 use std::{
     error::Error,
     fmt::{self, Debug, Display, Formatter},
+    io,
 };
 
 #[derive(Debug, PartialEq)]
@@ -29,9 +31,13 @@ pub enum InputQueueError {
         given: FrameSize,
         first_incorrect_frame: FrameSize,
     },
+    /// Adding the input would overwrite one that is not confirmed yet
+    QueueFull {
+        given: FrameSize,
+        tail_frame: FrameSize,
+    },
     FrameNotFound(FrameSize),
     GetDurningPrediction,
-    BadInput,
 }
 
 impl Display for InputQueueError {
@@ -62,13 +68,17 @@ impl Display for InputQueueError {
                 "Tried to reset prediction to frame {}, which is ahead of the first_incorrect_frame of {}",
                 given, first_incorrect_frame
             ),
+            InputQueueError::QueueFull{given, tail_frame} => write!(
+                fmt,
+                "Tried to add frame number {}, which is too far ahead of the tail frame of {}",
+                given, tail_frame
+            ),
             InputQueueError::FrameNotFound(given) => {
                 write!(fmt, "Tried to request frame number of {}, which was not found", given)
             }
             InputQueueError::GetDurningPrediction => {
                 write!(fmt, "Attempted to get input when there is a prediction error. You need to rollback")
             }
-            InputQueueError::BadInput => write!(fmt, "Given input with None for frame number"),
         }
     }
 }
@@ -83,12 +93,7 @@ pub enum SyncError {
         frames_behind: FrameSize,
         max_prediction_frames: FrameSize,
     },
-    SimulationError {
-        given: FrameSize,
-        expected: FrameSize,
-    },
     StateNotFound(FrameSize),
-    NotInRollback,
 }
 
 impl Display for SyncError {
@@ -108,15 +113,9 @@ impl Display for SyncError {
                 "Rejecting input prediction barrier: currently {} frames behind with max_prediction_frames: {} ",
                 frames_behind, max_prediction_frames
             ),
-            SyncError::SimulationError{given, expected} => write!(
-                fmt,
-                "After rolling back frame count is at {}, when it should be at {}",
-                given, expected
-            ),
             SyncError::StateNotFound(frame) => {
                 write!(fmt, "frame {} not found in saved states", frame)
-            },
-            SyncError::NotInRollback => write!(fmt, "Called post_adjust_simulation when not in a rollback")
+            }
         }
     }
 }
@@ -128,3 +127,153 @@ impl From<InputQueueError> for SyncError {
         SyncError::QueueError(inner)
     }
 }
+
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The socket or transport failed
+    Io(io::Error),
+    /// A message could not be serialized
+    Encoding(bincode::Error),
+    /// A message serialized to more than the max packet size
+    PacketTooLarge { size: usize, max: usize },
+    /// The input codec could not encode one of our inputs
+    InputEncoding,
+}
+
+impl Display for NetworkError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkError::Io(e) => write!(fmt, "Network io error: {}", e),
+            NetworkError::Encoding(e) => write!(fmt, "Failed to encode message: {}", e),
+            NetworkError::InputEncoding => write!(fmt, "Input codec failed to encode an input"),
+            NetworkError::PacketTooLarge { size, max } => write!(
+                fmt,
+                "Message is {} bytes which is more than the max packet size of {}",
+                size, max
+            ),
+        }
+    }
+}
+
+impl Error for NetworkError {}
+
+impl From<io::Error> for NetworkError {
+    fn from(inner: io::Error) -> Self {
+        NetworkError::Io(inner)
+    }
+}
+
+impl From<ErrorKind> for NetworkError {
+    fn from(inner: ErrorKind) -> Self {
+        match inner {
+            ErrorKind::IOError(e) => NetworkError::Io(e),
+            e => NetworkError::Io(io::Error::new(io::ErrorKind::Other, e.to_string())),
+        }
+    }
+}
+
+impl From<bincode::Error> for NetworkError {
+    fn from(inner: bincode::Error) -> Self {
+        NetworkError::Encoding(inner)
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    SyncError(SyncError),
+    NetworkError(NetworkError),
+    UnknownPlayer(PlayerHandle),
+    NotSynchronized,
+    /// The frame delay changes on this frame and some players' proposals
+    /// have not arrived yet, try again after polling
+    FrameDelayPending,
+    /// The sync test resimulated a frame and got a different checksum
+    MismatchedChecksum {
+        frame: FrameSize,
+        original: u64,
+        resimulated: u64,
+    },
+}
+
+impl Display for SessionError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::SyncError(e) => write!(fmt, "Sync error: {}", e),
+            SessionError::NetworkError(e) => write!(fmt, "Network error: {}", e),
+            SessionError::UnknownPlayer(handle) => {
+                write!(fmt, "Player {} is not part of this session", handle)
+            }
+            SessionError::NotSynchronized => write!(fmt, "Still synchronizing with remote players"),
+            SessionError::FrameDelayPending => write!(
+                fmt,
+                "Waiting for the other players to agree on the frame delay"
+            ),
+            SessionError::MismatchedChecksum {
+                frame,
+                original,
+                resimulated,
+            } => write!(
+                fmt,
+                "Checksum for frame {} was {} but {} after resimulating",
+                frame, original, resimulated
+            ),
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+impl From<SyncError> for SessionError {
+    fn from(inner: SyncError) -> Self {
+        SessionError::SyncError(inner)
+    }
+}
+
+impl From<InputQueueError> for SessionError {
+    fn from(inner: InputQueueError) -> Self {
+        SessionError::SyncError(inner.into())
+    }
+}
+
+impl From<NetworkError> for SessionError {
+    fn from(inner: NetworkError) -> Self {
+        SessionError::NetworkError(inner)
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    Encoding(bincode::Error),
+    /// The file does not start with the replay header
+    NotAReplay,
+    /// The replay was written by a version of the library we can't read
+    UnsupportedVersion(u16),
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(fmt, "Failed to read or write replay: {}", e),
+            ReplayError::Encoding(e) => write!(fmt, "Failed to encode replay: {}", e),
+            ReplayError::NotAReplay => write!(fmt, "File is not a replay"),
+            ReplayError::UnsupportedVersion(version) => {
+                write!(fmt, "Replay version {} is not supported", version)
+            }
+        }
+    }
+}
+
+impl Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+    fn from(inner: io::Error) -> Self {
+        ReplayError::Io(inner)
+    }
+}
+
+impl From<bincode::Error> for ReplayError {
+    fn from(inner: bincode::Error) -> Self {
+        ReplayError::Encoding(inner)
+    }
+}
@@ -0,0 +1,322 @@
+use crate::{FrameIndex, FrameSize, PlayerHandle};
+use std::{
+    cmp::{max, min, Ordering},
+    collections::BTreeMap,
+    time::Duration,
+};
+
+/// Most rounds that can be waiting to be applied, more than that and a peer
+/// is sending proposals for rounds far in the future
+const MAX_PENDING_ROUNDS: usize = 8;
+
+/// Settings for adjusting the local frame delay during a match
+#[derive(Debug, Clone)]
+pub struct FrameDelayConfig {
+    /// Frames between adjustments, every player has to use the same interval
+    /// so they agree on when the delay changes
+    pub interval: FrameSize,
+    pub min_delay: FrameSize,
+    pub max_delay: FrameSize,
+    /// Length of a frame, used to convert the round trip time to frames
+    pub frame_duration: Duration,
+    /// Raise the delay when more than this fraction of frames rolled back
+    pub max_rollback_rate: f32,
+    /// Only lower the delay when fewer than this fraction of frames rolled
+    /// back
+    pub min_rollback_rate: f32,
+}
+
+impl Default for FrameDelayConfig {
+    fn default() -> Self {
+        Self {
+            interval: 120,
+            min_delay: 0,
+            max_delay: 6,
+            frame_duration: Duration::from_micros(16_667),
+            max_rollback_rate: 0.5,
+            min_rollback_rate: 0.1,
+        }
+    }
+}
+
+/// Picks a frame delay from the round trip time and how often we roll back
+///
+/// Every `interval` frames each player proposes a delay, the largest
+/// proposal of a round is used by everyone starting exactly one interval
+/// after the round so every peer has time to hear about it. A player that
+/// reaches that frame before every proposal arrived has to wait for them.
+/// Players that don't propose for a round, because they turned this on
+/// later or not at all, answer a proposal with the delay they are using.
+pub struct AdaptiveFrameDelay {
+    config: FrameDelayConfig,
+    /// Delay currently in use
+    delay: FrameSize,
+    frames: u32,
+    rollbacks: u32,
+    /// Proposals for each round that has not been applied yet
+    proposals: BTreeMap<FrameSize, Vec<(PlayerHandle, FrameSize)>>,
+    /// Last round that was applied, proposals for it or earlier rounds are
+    /// ignored
+    applied_round: FrameIndex,
+    /// Last round before this was turned on, we never propose for it or
+    /// any earlier round
+    enabled_after: FrameSize,
+}
+
+impl AdaptiveFrameDelay {
+    /// Start adjusting on `frame`, the first proposal is made on the next
+    /// round after it
+    pub fn new(config: FrameDelayConfig, delay: FrameSize, frame: FrameSize) -> Self {
+        let enabled_after = frame - frame % config.interval;
+        Self {
+            config,
+            delay,
+            frames: 0,
+            rollbacks: 0,
+            proposals: BTreeMap::new(),
+            applied_round: Some(enabled_after),
+            enabled_after,
+        }
+    }
+
+    pub fn delay(&self) -> FrameSize {
+        self.delay
+    }
+
+    /// True if our proposal for the next round should be made on this frame
+    pub fn is_round(&self, frame: FrameSize) -> bool {
+        frame > 0 && frame % self.config.interval == 0
+    }
+
+    /// Record a simulated frame and whether it needed a rollback
+    pub fn record_frame(&mut self, rolled_back: bool) {
+        self.frames += 1;
+        if rolled_back {
+            self.rollbacks += 1;
+        }
+    }
+
+    /// Delay we want given the round trip time in milliseconds to the
+    /// slowest peer, based on the frames recorded since the last call. The
+    /// delay moves by at most one frame per round.
+    pub fn recommend(&mut self, round_trip_time: u64) -> FrameSize {
+        let rollback_rate = if self.frames == 0 {
+            0.0
+        } else {
+            self.rollbacks as f32 / self.frames as f32
+        };
+        self.frames = 0;
+        self.rollbacks = 0;
+
+        // frames it takes our input to reach the peer
+        let frame_millis = max(self.config.frame_duration.as_millis() as u64, 1);
+        let latency = ((round_trip_time / 2 + frame_millis - 1) / frame_millis) as FrameSize;
+        let target = if rollback_rate > self.config.max_rollback_rate {
+            self.delay + 1
+        } else if rollback_rate < self.config.min_rollback_rate {
+            latency
+        } else {
+            // rolling back a bit, don't lower the delay yet
+            max(self.delay, latency)
+        };
+        let next = match target.cmp(&self.delay) {
+            Ordering::Greater => self.delay + 1,
+            Ordering::Less => self.delay - 1,
+            Ordering::Equal => self.delay,
+        };
+        max(min(next, self.config.max_delay), self.config.min_delay)
+    }
+
+    /// Store a player's proposal for the round starting on `round`, remote
+    /// proposals are not trusted so the delay is kept within the configured
+    /// range and proposals for rounds that can't happen are ignored
+    pub fn add_proposal(&mut self, round: FrameSize, player: PlayerHandle, delay: FrameSize) {
+        let already_applied = self.applied_round.map_or(false, |applied| round <= applied);
+        let too_many_rounds =
+            self.proposals.len() >= MAX_PENDING_ROUNDS && !self.proposals.contains_key(&round);
+        if !self.is_round(round) || already_applied || too_many_rounds {
+            return;
+        }
+        let delay = max(min(delay, self.config.max_delay), self.config.min_delay);
+        let proposals = self.proposals.entry(round).or_insert_with(Vec::new);
+        if !proposals
+            .iter()
+            .any(|(proposed_by, _)| *proposed_by == player)
+        {
+            proposals.push((player, delay));
+        }
+    }
+
+    /// Round whose delay starts on this frame
+    fn due_round(&self, frame: FrameSize) -> Option<FrameSize> {
+        frame
+            .checked_sub(self.config.interval)
+            .filter(|round| self.is_round(*round))
+    }
+
+    /// True if we made no proposal for this round, proposals for it have to
+    /// be answered with the delay we are using so the proposer isn't stuck
+    /// waiting
+    pub fn needs_answer(&self, round: FrameSize) -> bool {
+        round <= self.enabled_after
+    }
+
+    /// True if `player` proposed for the round whose delay starts on this
+    /// frame and some other players' proposals have not arrived yet
+    pub fn is_waiting(&self, frame: FrameSize, player: PlayerHandle) -> bool {
+        let round = match self.due_round(frame) {
+            Some(round) => round,
+            None => return false,
+        };
+        let applied = self.applied_round.map_or(false, |applied| round <= applied);
+        let proposed = self.proposals.get(&round).map_or(false, |proposals| {
+            proposals
+                .iter()
+                .any(|(proposed_by, _)| *proposed_by == player)
+        });
+        !applied && proposed
+    }
+
+    /// The new delay if a round's delay starts on `frame` and every one of
+    /// `players` has proposed for it. Rounds up to that one are dropped.
+    pub fn agreed_delay(
+        &mut self,
+        frame: FrameSize,
+        players: &[PlayerHandle],
+    ) -> Option<FrameSize> {
+        let round = self.due_round(frame)?;
+        let proposals = self.proposals.get(&round)?;
+        let complete = players.iter().all(|player| {
+            proposals
+                .iter()
+                .any(|(proposed_by, _)| proposed_by == player)
+        });
+        if !complete {
+            return None;
+        }
+        let delay = proposals
+            .iter()
+            .map(|(_, delay)| *delay)
+            .max()
+            .unwrap_or(self.delay);
+        self.proposals = self.proposals.split_off(&(round + 1));
+        self.applied_round = Some(round);
+        self.delay = delay;
+        Some(delay)
+    }
+
+    /// Use this delay from now on, used when the delay is set by hand
+    pub fn set_delay(&mut self, delay: FrameSize) {
+        self.delay = delay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(delay: FrameSize) -> AdaptiveFrameDelay {
+        AdaptiveFrameDelay::new(
+            FrameDelayConfig {
+                interval: 10,
+                frame_duration: Duration::from_millis(10),
+                ..FrameDelayConfig::default()
+            },
+            delay,
+            0,
+        )
+    }
+
+    fn record(controller: &mut AdaptiveFrameDelay, frames: u32, rollbacks: u32) {
+        for frame in 0..frames {
+            controller.record_frame(frame < rollbacks);
+        }
+    }
+
+    #[test]
+    fn test_recommend() {
+        let mut delay = controller(0);
+        // 60ms round trip is 3 frames one way, approached a frame at a time
+        assert_eq!(delay.recommend(60), 1);
+        delay.set_delay(3);
+        assert_eq!(delay.recommend(60), 3);
+
+        // rolling back a lot raises it past the latency
+        record(&mut delay, 10, 8);
+        assert_eq!(delay.recommend(60), 4);
+        delay.set_delay(4);
+        // some rollbacks keeps it where it is
+        record(&mut delay, 10, 3);
+        assert_eq!(delay.recommend(60), 4);
+        // few rollbacks lowers it back to the latency
+        record(&mut delay, 10, 0);
+        assert_eq!(delay.recommend(60), 3);
+
+        delay.set_delay(6);
+        record(&mut delay, 10, 10);
+        assert_eq!(delay.recommend(500), 6, "capped at max_delay");
+    }
+
+    #[test]
+    fn test_agreed_delay() {
+        let mut delay = controller(2);
+        assert!(delay.is_round(10));
+        assert!(!delay.is_round(0));
+        assert!(!delay.is_round(15));
+
+        delay.add_proposal(10, 0, 3);
+        assert_eq!(delay.agreed_delay(20, &[0, 1]), None);
+        delay.add_proposal(10, 1, 1);
+        // not applied until a full interval after the round
+        assert_eq!(delay.agreed_delay(19, &[0, 1]), None);
+        assert_eq!(delay.agreed_delay(20, &[0, 1]), Some(3));
+        assert_eq!(delay.delay(), 3);
+        assert_eq!(delay.agreed_delay(21, &[0, 1]), None);
+
+        // an incomplete round has to be waited for on the frame it is due
+        delay.add_proposal(20, 0, 2);
+        assert!(delay.is_waiting(30, 0));
+        assert!(!delay.is_waiting(30, 1), "only players that proposed wait");
+        assert_eq!(delay.agreed_delay(30, &[0, 1]), None);
+        delay.add_proposal(20, 1, 2);
+        assert_eq!(delay.agreed_delay(30, &[0, 1]), Some(2));
+        assert!(!delay.is_waiting(30, 0));
+        assert!(!delay.is_waiting(35, 0));
+
+        // rounds that were applied or can't happen are ignored
+        delay.add_proposal(20, 0, 5);
+        delay.add_proposal(25, 0, 5);
+        delay.add_proposal(FrameSize::max_value(), 0, 5);
+        assert!(delay.proposals.is_empty());
+        assert_eq!(delay.agreed_delay(FrameSize::max_value(), &[0]), None);
+
+        // remote proposals are kept within the configured delays
+        delay.add_proposal(30, 0, 1000);
+        delay.add_proposal(30, 1, 0);
+        assert_eq!(delay.agreed_delay(40, &[0, 1]), Some(6));
+    }
+
+    #[test]
+    fn test_enabled_mid_match() {
+        let mut delay = AdaptiveFrameDelay::new(
+            FrameDelayConfig {
+                interval: 10,
+                ..FrameDelayConfig::default()
+            },
+            2,
+            15,
+        );
+        // nobody proposed for round 10 so there is nothing to wait for
+        assert!(!delay.is_waiting(20, 0));
+        assert!(delay.needs_answer(10));
+        delay.add_proposal(10, 1, 4);
+        assert_eq!(delay.agreed_delay(20, &[0, 1]), None);
+
+        // round 20 is ours
+        assert!(!delay.needs_answer(20));
+        delay.add_proposal(20, 0, 3);
+        assert!(delay.is_waiting(30, 0));
+        delay.add_proposal(20, 1, 2);
+        assert_eq!(delay.agreed_delay(30, &[0, 1]), Some(3));
+    }
+}
@@ -1,40 +1,33 @@
 use crate::{FrameSize, GameInput};
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
-#[derive(Debug, Clone, PartialEq)]
-// TODO: i think only prediction could have both of these be none
-// so might be better if i make a special type for prediction
-// so normal game input does not have to deal with unwraps
-pub struct GameInputFrame<T: GameInput> {
-    pub frame: Option<FrameSize>,
-    pub input: Option<T>,
+
+/// A player's real input for a frame, either added locally or received from
+/// their peer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfirmedInput<T: GameInput> {
+    pub frame: FrameSize,
+    pub input: T,
 }
 
-impl<T: GameInput> GameInputFrame<T> {
+impl<T: GameInput> ConfirmedInput<T> {
     pub fn new(input: T, frame: FrameSize) -> Self {
-        Self {
-            frame: Some(frame),
-            input: Some(input),
-        }
-    }
-
-    pub fn empty_input() -> Self {
-        Self {
-            frame: None,
-            input: None,
-        }
-    }
-
-    pub fn erase_input(&mut self) {
-        self.input = None
+        Self { frame, input }
     }
 }
 
 // Mostly used for tests to make frames easily
-impl<T: GameInput> From<(T, FrameSize)> for GameInputFrame<T> {
+impl<T: GameInput> From<(T, FrameSize)> for ConfirmedInput<T> {
     fn from(inner: (T, FrameSize)) -> Self {
-        Self {
-            input: Some(inner.0),
-            frame: Some(inner.1),
-        }
+        Self::new(inner.0, inner.1)
     }
 }
+
+/// Our guess at a player's input while we wait for the real one, `frame` is
+/// the first frame the guess has not been checked against a real input yet.
+/// None if the guess is that there is no input
+#[derive(Debug, Clone, PartialEq)]
+pub struct PredictedInput<T: GameInput> {
+    pub frame: FrameSize,
+    pub input: Option<T>,
+}
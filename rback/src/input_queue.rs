@@ -1,20 +1,32 @@
 use crate::{
-    error::InputQueueError, game_input_frame::GameInputFrame, FrameIndex, FrameSize, GameInput,
+    error::InputQueueError,
+    game_input_frame::{ConfirmedInput, PredictedInput},
+    prediction::{PredictionStrategy, RepeatLastInput},
+    FrameIndex, FrameSize, GameInput, PlayerInput,
 };
 use log::info;
-use std::{cmp::min, collections::VecDeque};
+use std::{cmp::min, sync::Arc};
+
+/// Number of frames each queue can hold
+// https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/input_queue.h#L13
+pub const INPUT_QUEUE_LENGTH: usize = 128;
 
 // TODO: simplify errors to only be the errors that could be thrown in that func
 
-#[derive(Debug)]
 /// Queue of inputs for a single player in the game
+///
+/// Inputs are kept in a ring buffer indexed by `frame % INPUT_QUEUE_LENGTH`
+/// which is allocated once, so every frame from `tail_frame` up to
+/// `last_added_frame` can be looked up directly.
 pub struct InputQueue<T: GameInput> {
-    // TODO: fixed-vec-deque crate?
-    // TODO: seems to be used like a stack so maybe just normal vec?
-    queue: VecDeque<GameInputFrame<T>>, /* TODO: maybe make this a box type
-                                         * to reduce/remove clones */
+    inputs: Vec<Option<ConfirmedInput<T>>>,
+    /// Oldest frame still in the queue
+    tail_frame: FrameSize,
     frame_delay: FrameSize,
-    prediction: GameInputFrame<T>,
+    /// Set while we are handing out guesses for frames we don't have yet
+    prediction: Option<PredictedInput<T>>,
+    /// Guesses the input for frames we don't have yet
+    prediction_strategy: Arc<dyn PredictionStrategy<T>>,
     /// Frame number of the last user added input
     last_user_added_frame: FrameIndex,
     pub(crate) last_added_frame: FrameIndex,
@@ -30,27 +42,46 @@ impl<T: GameInput> Default for InputQueue<T> {
 
 impl<T: GameInput> InputQueue<T> {
     pub fn new() -> Self {
-        // TODO: maybe use with capacity or reserve size of queue to prevent
-        // extra allocations
         Self {
-            queue: VecDeque::new(),
+            inputs: vec![None; INPUT_QUEUE_LENGTH],
+            tail_frame: 0,
             last_user_added_frame: None,
             last_added_frame: None,
             frame_delay: 0,
-            prediction: GameInputFrame::empty_input(),
+            prediction: None,
+            prediction_strategy: Arc::new(RepeatLastInput),
             first_incorrect_frame: None,
             last_frame_requested: None,
         }
     }
 
+    /// The input for a frame if it is still in the queue
+    #[inline]
+    fn get(&self, frame: FrameSize) -> Option<&ConfirmedInput<T>> {
+        if frame < self.tail_frame || self.last_added_frame.map_or(true, |last| frame > last) {
+            return None;
+        }
+        let input = self.inputs[frame as usize % INPUT_QUEUE_LENGTH].as_ref();
+        debug_assert_eq!(
+            input.map(|input| input.frame),
+            Some(frame),
+            "ring buffer slot does not hold the frame it should"
+        );
+        input
+    }
+
+    /// Most recently added input
+    #[inline]
+    fn last_added(&self) -> Option<&ConfirmedInput<T>> {
+        self.last_added_frame.and_then(|frame| self.get(frame))
+    }
+
     #[inline]
     fn check_sequential(
         last_frame: FrameIndex,
-        input_frame: FrameIndex,
+        input_frame: FrameSize,
         user_input: bool,
     ) -> Result<FrameSize, InputQueueError> {
-        let input_frame = input_frame.ok_or(InputQueueError::BadInput)?;
-
         if let Some(last_user_added_frame) = last_frame {
             // input must be added sequentially
             if input_frame != last_user_added_frame + 1 {
@@ -70,124 +101,105 @@ impl<T: GameInput> InputQueue<T> {
         Ok(input_frame)
     }
 
-    /// Called each frame by the game to get an input for this player
+    /// Called each frame by the game to get an input for this player, if we
+    /// don't have it yet the input is predicted
     pub fn get_input(
         &mut self,
         requested_frame: FrameSize,
-    ) -> Result<GameInputFrame<T>, InputQueueError> {
+    ) -> Result<PlayerInput<T>, InputQueueError> {
         if self.first_incorrect_frame.is_some() {
             // https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/input_queue.cpp#L122
             return Err(InputQueueError::GetDurningPrediction);
         }
-        let tail_frame = match self.queue.back() {
-            Some(tail) => {
-                let tail_frame = tail.frame.expect("Queue inputs should have a frame set");
-                if requested_frame < tail_frame {
-                    return Err(InputQueueError::BadFrameIndex {
-                        given: requested_frame,
-                        tail_frame,
-                    });
-                }
-                tail_frame
-            }
-            // if there isn't anything in the queue we are gonna predict
-            None => 0,
-        };
+        if requested_frame < self.tail_frame {
+            return Err(InputQueueError::BadFrameIndex {
+                given: requested_frame,
+                tail_frame: self.tail_frame,
+            });
+        }
 
         self.last_frame_requested = Some(requested_frame);
-        if self.prediction.frame.is_none() {
-            let idx_from_back = (requested_frame - tail_frame) as usize;
-            if idx_from_back < self.queue.len() {
+        if self.prediction.is_none() {
+            if let Some(desired_input) = self.get(requested_frame) {
                 // Valid frame no need to predict
-                let q_idx = (self.queue.len() - 1) - idx_from_back;
-                let desired_input = self
-                    .queue
-                    .get(q_idx)
-                    .expect("Requested frame should be in the queue");
-                debug_assert_eq!(
-                    desired_input.frame,
-                    Some(requested_frame),
-                    "requested frame does not match with input in q. Got {:#?}, expected {}",
-                    desired_input.frame,
-                    requested_frame
-                );
-                return Ok(desired_input.clone());
+                return Ok(PlayerInput::confirmed(desired_input.input.clone()));
             }
 
             // We need to do some predictions since they want a frame we don't
             // have
-            if requested_frame == 0 {
+            let input = if requested_frame == 0 {
                 info!("basing new prediction frame from nothing, you're client wants frame 0.");
-                self.prediction.erase_input();
-            } else if self.last_added_frame.is_none() {
-                info!("basing new prediction frame from nothing, since we have no frames yet.");
-                self.prediction.erase_input();
-            } else {
-                let previous = self
-                    .queue
-                    .front()
-                    .expect("Queue should be non empty to guess prediction");
+                self.prediction_strategy.predict(None)
+            } else if let Some(previous) = self.last_added() {
                 info!(
                     "basing new prediction frame from previously added frame (queue entry: {:?}).",
                     previous
                 );
-                self.prediction = previous.clone();
-            }
+                self.prediction_strategy.predict(Some(&previous.input))
+            } else {
+                info!("basing new prediction frame from nothing, since we have no frames yet.");
+                self.prediction_strategy.predict(None)
+            };
             // TODO: ggpo has frame++ but i think thats because there None input is 0
-            self.prediction.frame = Some(requested_frame);
+            self.prediction = Some(PredictedInput {
+                frame: requested_frame,
+                input,
+            });
         }
-        // TODO: assert prediction frame is >= 0?
-        let mut input = self.prediction.clone();
-        input.frame = Some(requested_frame);
-        Ok(input)
+        let input = self
+            .prediction
+            .as_ref()
+            .and_then(|prediction| prediction.input.clone());
+        Ok(PlayerInput::predicted(input))
     }
 
+    /// Returns the input as it was added to the queue after the frame delay,
+    /// None if the delay was lowered so it did not need to be added
     pub fn add_input(
         &mut self,
-        input: GameInputFrame<T>,
-    ) -> Result<GameInputFrame<T>, InputQueueError> {
+        input: ConfirmedInput<T>,
+    ) -> Result<Option<ConfirmedInput<T>>, InputQueueError> {
         let input_frame = Self::check_sequential(self.last_user_added_frame, input.frame, true)?;
+        let added = match self.advance_queue_head(input_frame, &input)? {
+            Some(new_frame) => Some(self.add_delayed_input(input, new_frame)?),
+            // only get here if frame delay was lowered so it didn't needed to be added to q
+            None => None,
+        };
         self.last_user_added_frame = Some(input_frame);
-        let new_frame = self.advance_queue_head(input_frame)?;
-        if let Some(new_frame) = new_frame {
-            return self.add_delayed_input(input, new_frame);
-        }
-
-        // only get here if frame delay was lowered so it didn't needed to be added to q
-        let mut input = input;
-        input.frame = new_frame;
-        Ok(input)
+        Ok(added)
     }
 
     fn add_delayed_input(
         &mut self,
-        input: GameInputFrame<T>,
+        input: ConfirmedInput<T>,
         frame_num: FrameSize,
-    ) -> Result<GameInputFrame<T>, InputQueueError> {
+    ) -> Result<ConfirmedInput<T>, InputQueueError> {
         // do i need this assert? https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/input_queue.cpp#L221
 
-        let input_frame = match self.queue.front() {
-            Some(q_input) => {
-                let last_added_frame = q_input.frame.expect("All queue inputs should be set");
-                Self::check_sequential(Some(last_added_frame), Some(frame_num), false)?
-            }
-            None => 0,
-        };
+        let input_frame = Self::check_sequential(self.last_added_frame, frame_num, false)?;
+        if input_frame - self.tail_frame >= INPUT_QUEUE_LENGTH as FrameSize {
+            return Err(InputQueueError::QueueFull {
+                given: input_frame,
+                tail_frame: self.tail_frame,
+            });
+        }
         let mut input = input;
-        input.frame = Some(input_frame);
-        self.queue.push_front(input.clone());
-        self.last_added_frame = input.frame;
+        input.frame = input_frame;
+        self.inputs[input_frame as usize % INPUT_QUEUE_LENGTH] = Some(input.clone());
+        self.last_added_frame = Some(input_frame);
 
-        if let Some(prediction_frame) = self.prediction.frame {
+        if let Some(prediction) = self.prediction.as_mut() {
             debug_assert_eq!(
-                frame_num, prediction_frame,
+                frame_num, prediction.frame,
                 "need added input to be the prediction frame, got {}, expected {}",
-                frame_num, prediction_frame
+                frame_num, prediction.frame
             );
 
             // We have been doing predictions so check if what we have
             // prediction matched the inputs we got
-            if self.first_incorrect_frame.is_none() && self.prediction.input != input.input {
+            if self.first_incorrect_frame.is_none()
+                && prediction.input.as_ref() != Some(&input.input)
+            {
                 info!(
                     "frame {} does not match prediction.  marking error.",
                     frame_num
@@ -195,29 +207,29 @@ impl<T: GameInput> InputQueue<T> {
                 self.first_incorrect_frame = Some(frame_num);
             }
 
-            if self.prediction.frame == self.last_frame_requested
+            if Some(prediction.frame) == self.last_frame_requested
                 && self.first_incorrect_frame.is_none()
             {
                 info!("prediction is correct!  dumping out of prediction mode.");
-                self.prediction.frame = None;
+                self.prediction = None;
             } else {
-                // should be some here but this is cleaner than unwrapping
-                self.prediction.frame = self.prediction.frame.map(|f| f + 1);
+                prediction.frame += 1;
             }
         }
 
         Ok(input)
     }
 
-    fn advance_queue_head(&mut self, frame: FrameSize) -> Result<FrameIndex, InputQueueError> {
-        // expected frame is the 2nd input in the queue
-        let expected_frame = match self.queue.front() {
-            Some(input) => {
-                input
-                    .frame
-                    .expect("All inputs in queue should have a frame number set")
-                    + 1
-            }
+    /// Fill any frames skipped by raising the frame delay and return the
+    /// frame `input` should be added for
+    fn advance_queue_head(
+        &mut self,
+        frame: FrameSize,
+        input: &ConfirmedInput<T>,
+    ) -> Result<FrameIndex, InputQueueError> {
+        // expected frame is the one after the last added input
+        let expected_frame = match self.last_added_frame {
+            Some(frame) => frame + 1,
             // ggpo sets expected to 0 if `_first_frame` is true so
             // i think if theres no 2nd elm in the queue it would do the same
             // thing?
@@ -232,8 +244,9 @@ impl<T: GameInput> InputQueue<T> {
 
         for frame_num in expected_frame..frame {
             // https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/input_queue.cpp#L288
-            let last_input = self.queue.front().expect("queue should be non empty");
-            let last_input = last_input.clone();
+            // GGPO repeats a blank input for the first frames, we don't have
+            // one so the first input is used
+            let last_input = self.last_added().unwrap_or(input).clone();
             self.add_delayed_input(last_input, frame_num)?;
         }
         Ok(Some(frame))
@@ -243,7 +256,7 @@ impl<T: GameInput> InputQueue<T> {
     pub fn get_confirmed_input(
         &self,
         requested_frame: FrameSize,
-    ) -> Result<GameInputFrame<T>, InputQueueError> {
+    ) -> Result<&ConfirmedInput<T>, InputQueueError> {
         if let Some(first_incorrect_frame) = self.first_incorrect_frame {
             if requested_frame > first_incorrect_frame {
                 return Err(InputQueueError::BadFrameRequest {
@@ -252,29 +265,25 @@ impl<T: GameInput> InputQueue<T> {
                 });
             }
         }
-        // TODO: find based on tail?
-        let input = self.queue.iter().find(|input| match input.frame {
-            Some(frame_num) => frame_num == requested_frame,
-            None => false,
-        });
-        match input {
-            Some(game_input_frame) => Ok(game_input_frame.clone()),
-            None => Err(InputQueueError::FrameNotFound(requested_frame)),
-        }
+        self.get(requested_frame)
+            .ok_or(InputQueueError::FrameNotFound(requested_frame))
     }
 
+    /// Drop every input before this frame, the last requested and last added
+    /// inputs are always kept since they are still needed for predictions
     pub fn discard_confirmed_frames(&mut self, frame: FrameSize) {
-        let frame = match self.last_frame_requested {
-            Some(last_frame) => min(last_frame, frame),
-            None => frame,
-        };
-        self.queue.retain(|input| match input.frame {
-            // TODO: should be
-            // just greater or is
-            // >=  fine ?
-            Some(input_frame) => input_frame >= frame,
-            None => false,
-        });
+        // nothing to drop, the tail has to stay at the first frame that
+        // will be added
+        if self.last_added_frame.is_none() {
+            return;
+        }
+        let frame = self
+            .last_frame_requested
+            .iter()
+            .chain(self.last_added_frame.iter())
+            .fold(frame, |frame, last| min(frame, *last));
+        // slots are overwritten when reused so they don't need to be cleared
+        self.tail_frame = self.tail_frame.max(frame);
     }
 
     pub fn reset_prediction(&mut self, frame: FrameSize) -> Result<(), InputQueueError> {
@@ -286,47 +295,41 @@ impl<T: GameInput> InputQueue<T> {
                 });
             }
         }
-        self.prediction.frame = None;
+        self.prediction = None;
         self.first_incorrect_frame = None;
         self.last_frame_requested = None;
         Ok(())
     }
 
+    pub fn set_prediction_strategy(&mut self, strategy: Arc<dyn PredictionStrategy<T>>) {
+        self.prediction_strategy = strategy;
+    }
+
     pub fn set_frame_delay(&mut self, delay: FrameSize) {
         self.frame_delay = delay;
     }
 
-    pub fn get_length(self) -> usize {
-        self.queue.len()
+    pub fn get_length(&self) -> usize {
+        match self.last_added_frame {
+            Some(last_frame) => (last_frame + 1 - self.tail_frame) as usize,
+            None => 0,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::prediction::RepeatHeldInputs;
 
     #[test]
     fn test_add() {
         let mut q: InputQueue<&str> = InputQueue::new();
-        let input = GameInputFrame {
-            frame: Some(0),
-            input: Some("hi"),
-        };
-        let added = q.add_input(input).unwrap();
-        assert_eq!(
-            added,
-            GameInputFrame {
-                frame: Some(0),
-                input: Some("hi")
-            }
-        );
+        let added = q.add_input(ConfirmedInput::new("hi", 0)).unwrap();
+        assert_eq!(added, Some(ConfirmedInput::new("hi", 0)));
 
         // Try to add same frame number
-        let input = GameInputFrame {
-            frame: Some(0),
-            input: Some("hello"),
-        };
-        let added = q.add_input(input);
+        let added = q.add_input(ConfirmedInput::new("hello", 0));
         assert!(added.is_err());
         let err = added.err().unwrap();
         assert_eq!(
@@ -338,12 +341,7 @@ mod tests {
         );
 
         // try bad frame number
-        let input = GameInputFrame {
-            frame: Some(10),
-            input: Some("hello"),
-        };
-
-        let added = q.add_input(input);
+        let added = q.add_input(ConfirmedInput::new("hello", 10));
         assert!(added.is_err());
         let err = added.err().unwrap();
         assert_eq!(
@@ -355,76 +353,125 @@ mod tests {
         );
 
         // correct frame number
-        let input = GameInputFrame {
-            frame: Some(1),
-            input: Some("its real"),
-        };
-        let added = q.add_input(input).unwrap();
-        assert_eq!(
-            added,
-            GameInputFrame {
-                frame: Some(1),
-                input: Some("its real")
-            }
-        );
+        let added = q.add_input(ConfirmedInput::new("its real", 1)).unwrap();
+        assert_eq!(added, Some(ConfirmedInput::new("its real", 1)));
     }
 
     #[test]
     fn test_get_input() -> Result<(), InputQueueError> {
         let mut q: InputQueue<&str> = InputQueue::new();
 
-        let input = GameInputFrame {
-            frame: Some(0),
-            input: Some("hi"),
-        };
-        q.add_input(input)?;
-        let input = GameInputFrame {
-            frame: Some(1),
-            input: Some("hello"),
-        };
-        q.add_input(input)?;
+        q.add_input(ConfirmedInput::new("hi", 0))?;
+        q.add_input(ConfirmedInput::new("hello", 1))?;
 
         // get good frames
-        assert_eq!(
-            q.get_input(0)?,
-            GameInputFrame {
-                frame: Some(0),
-                input: Some("hi"),
-            }
-        );
-        assert_eq!(
-            q.get_input(1)?,
-            GameInputFrame {
-                frame: Some(1),
-                input: Some("hello"),
-            }
-        );
+        assert_eq!(q.get_input(0)?, PlayerInput::confirmed("hi"));
+        assert_eq!(q.get_input(1)?, PlayerInput::confirmed("hello"));
 
         // TODO: add test when requested_frame < tail_frame
         // TODO: test empty predictions, i think the queue has to be empty for
         // these and we error
 
         // get bad frame so should try to predict based on last added frame
-        assert_eq!(
-            q.get_input(3)?,
-            GameInputFrame {
-                frame: Some(3),
-                input: Some("hello"),
+        assert_eq!(q.get_input(3)?, PlayerInput::predicted(Some("hello")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prediction_strategy() -> Result<(), InputQueueError> {
+        let mut q: InputQueue<&'static str> = InputQueue::new();
+        // jumping only happens on the frame it is pressed
+        q.set_prediction_strategy(Arc::new(RepeatHeldInputs::new(
+            |input: &&'static str| -> &'static str { input.trim_end_matches("+jump") },
+        )));
+        q.add_input(ConfirmedInput::new("left+jump", 0))?;
+        assert_eq!(q.get_input(1)?, PlayerInput::predicted(Some("left")));
+
+        // the prediction was wrong since they jumped again
+        q.add_input(ConfirmedInput::new("left+jump", 1))?;
+        assert_eq!(q.first_incorrect_frame, Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wraps_around() -> Result<(), InputQueueError> {
+        let mut q: InputQueue<FrameSize> = InputQueue::new();
+        for frame in 0..INPUT_QUEUE_LENGTH as FrameSize * 3 {
+            q.add_input(ConfirmedInput::new(frame, frame))?;
+            assert_eq!(q.get_input(frame)?, PlayerInput::confirmed(frame));
+            if frame >= 4 {
+                q.discard_confirmed_frames(frame - 4);
             }
+            assert_eq!(q.get_length(), min(frame as usize + 1, 5));
+        }
+        let last_frame = INPUT_QUEUE_LENGTH as FrameSize * 3 - 1;
+        assert_eq!(q.get_confirmed_input(last_frame - 4)?.input, last_frame - 4);
+        assert_eq!(
+            q.get_confirmed_input(last_frame - 5),
+            Err(InputQueueError::FrameNotFound(last_frame - 5))
         );
         Ok(())
     }
 
     #[test]
-    fn test_get_empty() -> Result<(), InputQueueError> {
-        let mut q: InputQueue<&str> = InputQueue::new();
+    fn test_queue_full() -> Result<(), InputQueueError> {
+        let mut q: InputQueue<FrameSize> = InputQueue::new();
+        for frame in 0..INPUT_QUEUE_LENGTH as FrameSize {
+            q.add_input(ConfirmedInput::new(frame, frame))?;
+        }
+        // nothing was confirmed so frame 0 can't be overwritten
+        let full = INPUT_QUEUE_LENGTH as FrameSize;
         assert_eq!(
-            q.get_input(3)?,
-            GameInputFrame {
-                frame: Some(3),
-                input: None,
-            }
+            q.add_input(ConfirmedInput::new(full, full)),
+            Err(InputQueueError::QueueFull {
+                given: full,
+                tail_frame: 0
+            })
         );
         Ok(())
     }
+
+    #[test]
+    fn test_frame_delay() -> Result<(), InputQueueError> {
+        let mut q: InputQueue<FrameSize> = InputQueue::new();
+        q.set_frame_delay(2);
+        // the frames before the delay repeat the first input
+        assert_eq!(q.add_input((10, 0).into())?, Some((10, 2).into()));
+        assert_eq!(q.get_confirmed_input(0)?, &(10, 0).into());
+        assert_eq!(q.get_confirmed_input(1)?, &(10, 1).into());
+
+        // raising the delay repeats the last input for the skipped frame
+        q.set_frame_delay(3);
+        assert_eq!(q.add_input((11, 1).into())?, Some((11, 4).into()));
+        assert_eq!(q.get_confirmed_input(3)?, &(10, 3).into());
+
+        // lowering it drops inputs until the frames catch up
+        q.set_frame_delay(1);
+        assert_eq!(q.add_input((12, 2).into())?, None);
+        assert_eq!(q.add_input((13, 3).into())?, None);
+        assert_eq!(q.add_input((14, 4).into())?, Some((14, 5).into()));
+        assert_eq!(q.get_length(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_empty() -> Result<(), InputQueueError> {
+        let mut q: InputQueue<&str> = InputQueue::new();
+        assert_eq!(q.get_input(3)?, PlayerInput::predicted(None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_discard_empty() -> Result<(), InputQueueError> {
+        let mut q: InputQueue<FrameSize> = InputQueue::new();
+        for frame in 0..6 {
+            assert_eq!(q.get_input(frame)?, PlayerInput::predicted(None));
+        }
+        q.discard_confirmed_frames(5);
+        assert_eq!(q.get_length(), 0);
+        // the first input still goes in at frame 0
+        assert_eq!(q.add_input((1, 0).into())?, Some((1, 0).into()));
+        assert_eq!(q.get_confirmed_input(0)?, &(1, 0).into());
+        Ok(())
+    }
 }
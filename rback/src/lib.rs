@@ -1,12 +1,20 @@
 // #![warn(missing_docs)]
 
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use session::SessionEvent;
 use std::fmt::Debug;
 
 pub mod error;
+pub mod frame_delay;
 pub(crate) mod game_input_frame;
 pub mod input_queue;
 pub mod network;
+pub mod prediction;
+pub mod replay;
+pub mod saved_states;
+pub mod session;
 pub mod sync;
+pub mod time_sync;
 // With this we can keep track of about 3 years worth of frames
 // at 60fps...
 type FrameSize = u32;
@@ -15,31 +23,98 @@ pub(crate) type FrameIndex = Option<FrameSize>;
 pub trait GameInput: Clone + Debug + PartialEq {}
 impl<T> GameInput for T where T: Clone + Debug + PartialEq {}
 
+/// Input sent to remote players also needs to be serializable
+pub trait NetworkInput: GameInput + Serialize + DeserializeOwned {}
+impl<T> NetworkInput for T where T: GameInput + Serialize + DeserializeOwned {}
+
+/// Index of a player's input queue
+pub type PlayerHandle = u8;
+
+/// GGPO style callbacks, an alternative to handling the returned requests
+/// yourself. Pass an implementation to a session's `*_with_callbacks`
+/// methods and it will save, load and advance the game for you.
 pub trait SyncCallBacks {
-    type SavedState;
+    type Input: GameInput;
+    type SavedState: Clone;
     // Don't need to use frame in save/load passed for convince if caller wants to
     // also keep track of states
     fn save_game_state(&mut self, frame: FrameSize) -> Self::SavedState;
+    /// Checksum of a saved state, compared with the other players to detect
+    /// desyncs
+    fn checksum(&mut self, _saved_state: &Self::SavedState, _frame: FrameSize) -> Option<u64> {
+        None
+    }
     fn load_game_state(&mut self, saved_state: Self::SavedState, frame: FrameSize);
-    fn advance_frame(&mut self);
-    fn on_event();
+    fn advance_frame(&mut self, inputs: Vec<PlayerInput<Self::Input>>);
+    fn on_event(&mut self, event: SessionEvent);
 }
 
+/// Requests returned when advancing a frame, the game must perform them in
+/// the order they are given
 #[derive(PartialEq, Debug)]
-pub struct SaveFrame {
-    pub frame: FrameSize,
+pub enum Request<T: GameInput, S> {
+    /// Save the current game state so it can be loaded by this frame later.
+    /// Hand the state back to the session with `save_game_state`
+    SaveGameState { frame: FrameSize },
+    /// Replace the current game state with the state saved for this frame
+    LoadGameState { frame: FrameSize, state: S },
+    /// Advance the game state by one frame using these inputs, each index
+    /// corresponds to the input for that player
+    AdvanceFrame { inputs: Vec<PlayerInput<T>> },
 }
 
-#[derive(PartialEq, Debug)]
-pub struct RollbackState {
-    pub frame: FrameSize,
-    pub num_steps: FrameSize,
+/// Whether the input given to the game for a player is real
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum InputStatus {
+    /// The input the player actually gave
+    Confirmed,
+    /// A guess since the player's input has not arrived yet, if it turns out
+    /// wrong the frame will be rolled back and resimulated
+    Predicted,
+    /// The player left the match so the game should stop waiting on them
+    Disconnected,
 }
 
-// pub enum RequiredAction {
-//     /// Save current game state which can be looked up by the frame param
-//     SaveState(SaveFrame),
-//     /// Load state corresponding to the frame param and advance your game
-// state     /// by num_steps
-//     Rollback(RollbackState),
-// }
+/// A player's input for a single frame
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerInput<T: GameInput> {
+    /// None if there is no input to use for the player, always None once they
+    /// have disconnected
+    pub input: Option<T>,
+    pub status: InputStatus,
+}
+
+impl<T: GameInput> PlayerInput<T> {
+    pub fn confirmed(input: T) -> Self {
+        Self {
+            input: Some(input),
+            status: InputStatus::Confirmed,
+        }
+    }
+
+    pub fn predicted(input: Option<T>) -> Self {
+        Self {
+            input,
+            status: InputStatus::Predicted,
+        }
+    }
+
+    pub fn disconnected() -> Self {
+        Self {
+            input: None,
+            status: InputStatus::Disconnected,
+        }
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.status == InputStatus::Confirmed
+    }
+
+    pub fn is_predicted(&self) -> bool {
+        self.status == InputStatus::Predicted
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.status == InputStatus::Disconnected
+    }
+}
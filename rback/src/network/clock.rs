@@ -0,0 +1,33 @@
+use std::time::{Duration, Instant};
+
+/// Where the network code gets the time from. Timeouts, keep alives, quality
+/// reports and pings all use it so they can run on a simulated clock.
+pub trait Clock: Send + Sync {
+    /// Time since some fixed point, never goes backwards
+    fn now(&self) -> Duration;
+}
+
+/// The system's monotonic clock, used unless the transport has its own
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
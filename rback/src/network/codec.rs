@@ -0,0 +1,261 @@
+use crate::{input_queue::INPUT_QUEUE_LENGTH, NetworkInput};
+use bincode::{deserialize, serialize};
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+
+/// Converts a single input to bytes and back. Games can implement this to
+/// pack their inputs into as few bytes as possible, by default inputs are
+/// encoded with bincode.
+pub trait InputCodec<T>: Send + Sync {
+    /// Returns None if the input can't be encoded
+    fn encode(&self, input: &T) -> Option<Vec<u8>>;
+    /// Returns None if the bytes are not a valid input
+    fn decode(&self, bytes: &[u8]) -> Option<T>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BincodeCodec;
+
+impl<T: NetworkInput> InputCodec<T> for BincodeCodec {
+    fn encode(&self, input: &T) -> Option<Vec<u8>> {
+        serialize(input).ok()
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Option<T> {
+        deserialize(bytes).ok()
+    }
+}
+
+/// A run of sequential inputs compressed for the wire.
+///
+/// Each input is encoded with an `InputCodec` and then only the bytes that
+/// changed from the previous input are stored (similar to GGPO xor-ing each
+/// input against the last one). Runs of identical inputs are stored once with
+/// a count so inputs held for many frames cost almost nothing.
+///
+/// Layout is a list of runs, each one is
+/// `run_length, encoded_len, num_changes, (byte_index, xor_value) *
+/// num_changes` where every number but `xor_value` is a LEB128 varint.
+/// A peer never has more than `INPUT_QUEUE_LENGTH` inputs to send so at most
+/// that many can be decoded.
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct EncodedInputs<T> {
+    data: Vec<u8>,
+    #[serde(skip)]
+    _input: PhantomData<T>,
+}
+
+impl<T: Clone> EncodedInputs<T> {
+    /// Returns None if the codec could not encode one of the inputs
+    pub fn encode<'a, I>(codec: &dyn InputCodec<T>, inputs: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = &'a T>,
+        T: 'a,
+    {
+        let mut data = Vec::new();
+        let mut prev: Vec<u8> = Vec::new();
+        let mut run: Option<(Vec<u8>, usize)> = None;
+        for input in inputs {
+            let encoded = codec.encode(input)?;
+            run = match run {
+                Some((bytes, count)) if bytes == encoded => Some((bytes, count + 1)),
+                Some((bytes, count)) => {
+                    write_run(&mut data, &prev, &bytes, count);
+                    prev = bytes;
+                    Some((encoded, 1))
+                }
+                None => Some((encoded, 1)),
+            };
+        }
+        if let Some((bytes, count)) = run {
+            write_run(&mut data, &prev, &bytes, count);
+        }
+        Some(Self {
+            data,
+            _input: PhantomData,
+        })
+    }
+
+    /// Returns None if the data is malformed or holds more than
+    /// `INPUT_QUEUE_LENGTH` inputs
+    pub fn decode(&self, codec: &dyn InputCodec<T>) -> Option<Vec<T>> {
+        let mut inputs = Vec::new();
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut cursor = 0;
+        while cursor < self.data.len() {
+            let run_length = read_varint(&self.data, &mut cursor)?;
+            let encoded_len = read_varint(&self.data, &mut cursor)?;
+            let num_changes = read_varint(&self.data, &mut cursor)?;
+            if run_length == 0
+                || run_length > INPUT_QUEUE_LENGTH - inputs.len()
+                || encoded_len > self.data.len() * 8 + 64
+            {
+                return None;
+            }
+            bytes.resize(encoded_len, 0);
+            for _ in 0..num_changes {
+                let idx = read_varint(&self.data, &mut cursor)?;
+                let xor = *self.data.get(cursor)?;
+                cursor += 1;
+                *bytes.get_mut(idx)? ^= xor;
+            }
+            let input = codec.decode(&bytes)?;
+            for _ in 0..run_length {
+                inputs.push(input.clone());
+            }
+        }
+        Some(inputs)
+    }
+
+    /// Size of the compressed inputs in bytes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+fn write_run(data: &mut Vec<u8>, prev: &[u8], bytes: &[u8], run_length: usize) {
+    let changes: Vec<(usize, u8)> = bytes
+        .iter()
+        .enumerate()
+        .map(|(idx, byte)| (idx, byte ^ prev.get(idx).copied().unwrap_or(0)))
+        .filter(|(_, xor)| *xor != 0)
+        .collect();
+    write_varint(data, run_length);
+    write_varint(data, bytes.len());
+    write_varint(data, changes.len());
+    for (idx, xor) in changes {
+        write_varint(data, idx);
+        data.push(xor);
+    }
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            data.push(byte);
+            return;
+        }
+        data.push(byte | 0x80);
+    }
+}
+
+fn read_varint(data: &[u8], cursor: &mut usize) -> Option<usize> {
+    let mut value: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*cursor)?;
+        *cursor += 1;
+        if shift >= 64 {
+            return None;
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let inputs: Vec<u16> = vec![0, 0, 1, 1, 1, 0x0101, 0x8000, 0x8000, 0];
+        let encoded = EncodedInputs::encode(&BincodeCodec, &inputs).unwrap();
+        assert_eq!(encoded.decode(&BincodeCodec), Some(inputs));
+
+        let encoded: EncodedInputs<u16> = EncodedInputs::encode(&BincodeCodec, &[]).unwrap();
+        assert!(encoded.is_empty());
+        assert_eq!(encoded.decode(&BincodeCodec), Some(vec![]));
+    }
+
+    #[test]
+    fn test_runs_are_compressed() {
+        // a button held for a second
+        let inputs = vec![0b0100_u32; 60];
+        let encoded = EncodedInputs::encode(&BincodeCodec, &inputs).unwrap();
+        assert!(encoded.len() < 8, "encoded to {} bytes", encoded.len());
+        assert_eq!(encoded.decode(&BincodeCodec), Some(inputs));
+    }
+
+    /// Packs a pair of bools into one byte
+    struct ButtonCodec;
+
+    impl InputCodec<(bool, bool)> for ButtonCodec {
+        fn encode(&self, input: &(bool, bool)) -> Option<Vec<u8>> {
+            Some(vec![input.0 as u8 | (input.1 as u8) << 1])
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Option<(bool, bool)> {
+            match bytes {
+                [byte] if *byte < 4 => Some((byte & 1 != 0, byte & 2 != 0)),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_codec() {
+        let inputs = vec![(false, false), (true, false), (true, true), (true, true)];
+        let encoded = EncodedInputs::encode(&ButtonCodec, &inputs).unwrap();
+        assert_eq!(encoded.decode(&ButtonCodec), Some(inputs));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let encoded: EncodedInputs<u16> = EncodedInputs {
+            data: vec![1, 2, 1, 9, 1],
+            _input: PhantomData,
+        };
+        assert_eq!(encoded.decode(&BincodeCodec), None);
+    }
+
+    #[test]
+    fn test_too_many_inputs() {
+        let inputs = vec![7_u8; INPUT_QUEUE_LENGTH];
+        let encoded = EncodedInputs::encode(&BincodeCodec, &inputs).unwrap();
+        assert_eq!(encoded.decode(&BincodeCodec), Some(inputs));
+
+        // a single run claiming 20 million inputs
+        let mut data = Vec::new();
+        write_run(&mut data, &[], &[7], 20_000_000);
+        let encoded: EncodedInputs<u8> = EncodedInputs {
+            data,
+            _input: PhantomData,
+        };
+        assert_eq!(encoded.decode(&BincodeCodec), None);
+
+        // runs that are fine alone but too many together
+        let mut inputs = vec![1_u8; INPUT_QUEUE_LENGTH];
+        inputs.push(2);
+        let encoded = EncodedInputs::encode(&BincodeCodec, &inputs).unwrap();
+        assert_eq!(encoded.decode(&BincodeCodec), None);
+    }
+
+    /// Fails to encode odd numbers
+    struct EvenCodec;
+
+    impl InputCodec<u8> for EvenCodec {
+        fn encode(&self, input: &u8) -> Option<Vec<u8>> {
+            Some(vec![*input]).filter(|_| input % 2 == 0)
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Option<u8> {
+            bytes.first().copied()
+        }
+    }
+
+    #[test]
+    fn test_encode_error() {
+        assert!(EncodedInputs::encode(&EvenCodec, &[2, 4]).is_some());
+        assert!(EncodedInputs::encode(&EvenCodec, &[2, 3]).is_none());
+    }
+}
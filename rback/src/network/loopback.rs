@@ -0,0 +1,320 @@
+use crate::{
+    error::NetworkError,
+    network::{
+        clock::Clock,
+        transport::{Delivery, Transport, TransportEvent},
+    },
+};
+use log::debug;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    cmp::max,
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+/// Shortest extra delay for packets that get reordered so they still arrive
+/// after later packets when there is no delay or jitter
+const MIN_REORDER_DELAY: Duration = Duration::from_millis(5);
+
+/// How badly the simulated network behaves. The default is a perfect network
+/// that delivers every packet on the next poll.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConditions {
+    /// Time every packet takes to arrive
+    pub delay: Duration,
+    /// Up to this much random extra delay per packet
+    pub jitter: Duration,
+    /// Chance from 0 to 1 an unreliable packet is dropped
+    pub packet_loss: f64,
+    /// Chance from 0 to 1 an unreliable packet arrives twice
+    pub duplicate_chance: f64,
+    /// Chance from 0 to 1 a packet is held back so it arrives after packets
+    /// sent after it
+    pub reorder_chance: f64,
+}
+
+/// Time on a loopback network, shared with every session on it so their
+/// timers only move when the network's clock is advanced
+struct LoopbackClock {
+    micros: AtomicU64,
+}
+
+impl Clock for LoopbackClock {
+    fn now(&self) -> Duration {
+        Duration::from_micros(self.micros.load(Ordering::SeqCst))
+    }
+}
+
+/// A packet on its way to an endpoint
+struct InFlight {
+    /// Time on the network's clock the packet arrives
+    deliver_at: Duration,
+    /// Keeps packets due at the same time in the order they were sent
+    sequence: u64,
+    payload: Vec<u8>,
+}
+
+struct Simulator {
+    conditions: NetworkConditions,
+    rng: StdRng,
+    /// Packets waiting to be received by each endpoint
+    inboxes: Vec<Vec<InFlight>>,
+    next_sequence: u64,
+    clock: Arc<LoopbackClock>,
+}
+
+impl Simulator {
+    fn send(&mut self, to: usize, payload: Vec<u8>, delivery: Delivery) {
+        let unreliable = delivery == Delivery::Unreliable;
+        if unreliable && self.rng.gen_bool(self.conditions.packet_loss) {
+            return;
+        }
+        let copies = if unreliable && self.rng.gen_bool(self.conditions.duplicate_chance) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let delay = self.random_delay();
+            self.inboxes[to].push(InFlight {
+                deliver_at: self.clock.now() + delay,
+                sequence: self.next_sequence,
+                payload: payload.clone(),
+            });
+            self.next_sequence += 1;
+        }
+    }
+
+    fn random_delay(&mut self) -> Duration {
+        let conditions = &self.conditions;
+        let jitter = self
+            .rng
+            .gen_range(0, conditions.jitter.as_micros() as u64 + 1);
+        let mut delay = conditions.delay + Duration::from_micros(jitter);
+        if self.rng.gen_bool(conditions.reorder_chance) {
+            delay += max(conditions.delay + conditions.jitter, MIN_REORDER_DELAY);
+        }
+        delay
+    }
+
+    /// Take every packet for the endpoint that has arrived by now
+    fn receive(&mut self, endpoint: usize) -> Vec<Vec<u8>> {
+        let now = self.clock.now();
+        let inbox = &mut self.inboxes[endpoint];
+        let mut arrived = Vec::new();
+        let mut i = 0;
+        while i < inbox.len() {
+            if inbox[i].deliver_at <= now {
+                arrived.push(inbox.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        arrived.sort_by_key(|packet| (packet.deliver_at, packet.sequence));
+        arrived.into_iter().map(|packet| packet.payload).collect()
+    }
+}
+
+/// In process network for testing sessions without sockets
+///
+/// Packets between the connected transports go through a simulator that can
+/// add delay, jitter, loss, duplication and reordering. Every random choice
+/// comes from a generator seeded with `seed` and the network has its own
+/// clock that only moves when `advance` is called, so the same packets are
+/// affected and arrive at the same time each run. The sessions using the
+/// transports run their timeouts, keep alives and pings on the same clock.
+/// Reliable packets are never dropped or duplicated.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+    simulator: Arc<Mutex<Simulator>>,
+    clock: Arc<LoopbackClock>,
+}
+
+impl LoopbackNetwork {
+    pub fn new(conditions: NetworkConditions, seed: u64) -> Self {
+        let clock = Arc::new(LoopbackClock {
+            micros: AtomicU64::new(0),
+        });
+        Self {
+            simulator: Arc::new(Mutex::new(Simulator {
+                conditions,
+                rng: StdRng::seed_from_u64(seed),
+                inboxes: Vec::new(),
+                next_sequence: 0,
+                clock: clock.clone(),
+            })),
+            clock,
+        }
+    }
+
+    /// Change the conditions for every packet sent from now on
+    pub fn set_conditions(&self, conditions: NetworkConditions) -> Result<(), NetworkError> {
+        lock(&self.simulator)?.conditions = conditions;
+        Ok(())
+    }
+
+    /// Move the network's clock forward, packets are delivered once it
+    /// reaches the time they arrive
+    pub fn advance(&self, elapsed: Duration) {
+        self.clock
+            .micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// Two transports that send to each other
+    pub fn connect(&self) -> Result<(LoopbackTransport, LoopbackTransport), NetworkError> {
+        let mut simulator = lock(&self.simulator)?;
+        let first = simulator.inboxes.len();
+        simulator.inboxes.push(Vec::new());
+        simulator.inboxes.push(Vec::new());
+        let transport = |endpoint, peer| LoopbackTransport {
+            simulator: self.simulator.clone(),
+            clock: self.clock.clone(),
+            endpoint,
+            peer,
+        };
+        Ok((transport(first, first + 1), transport(first + 1, first)))
+    }
+}
+
+/// Lock the simulator, a transport that panicked while holding it gives an
+/// error instead of panicking every other transport
+fn lock(simulator: &Mutex<Simulator>) -> Result<MutexGuard<Simulator>, NetworkError> {
+    simulator
+        .lock()
+        .map_err(|_| io::Error::new(ErrorKind::Other, "loopback network lock was poisoned").into())
+}
+
+/// One end of a connection made with `LoopbackNetwork::connect`
+pub struct LoopbackTransport {
+    simulator: Arc<Mutex<Simulator>>,
+    clock: Arc<LoopbackClock>,
+    endpoint: usize,
+    peer: usize,
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, payload: Vec<u8>, delivery: Delivery) -> Result<(), NetworkError> {
+        lock(&self.simulator)?.send(self.peer, payload, delivery);
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Vec<TransportEvent> {
+        let mut simulator = match lock(&self.simulator) {
+            Ok(simulator) => simulator,
+            Err(error) => {
+                debug!("not polling endpoint {}: {}", self.endpoint, error);
+                return Vec::new();
+            }
+        };
+        simulator
+            .receive(self.endpoint)
+            .into_iter()
+            .map(TransportEvent::Datagram)
+            .collect()
+    }
+
+    fn clock(&self) -> Option<Arc<dyn Clock>> {
+        Some(self.clock.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send numbered packets and return the numbers that arrived
+    fn send_packets(network: &LoopbackNetwork, delivery: Delivery) -> Vec<u8> {
+        let (mut local, mut remote) = network.connect().unwrap();
+        for i in 0..100 {
+            local.send(vec![i], delivery).unwrap();
+        }
+        assert_eq!(remote.poll(), vec![], "nothing arrives before the delay");
+        network.advance(Duration::from_millis(20));
+        remote
+            .poll()
+            .into_iter()
+            .map(|event| match event {
+                TransportEvent::Datagram(payload) => payload[0],
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_perfect_network() {
+        let network = LoopbackNetwork::new(NetworkConditions::default(), 0);
+        let (mut local, mut remote) = network.connect().unwrap();
+        local.send(vec![1], Delivery::Unreliable).unwrap();
+        local.send(vec![2], Delivery::Reliable).unwrap();
+        assert_eq!(local.poll(), vec![]);
+        assert_eq!(
+            remote.poll(),
+            vec![
+                TransportEvent::Datagram(vec![1]),
+                TransportEvent::Datagram(vec![2])
+            ]
+        );
+        assert_eq!(remote.poll(), vec![]);
+    }
+
+    #[test]
+    fn test_bad_network() {
+        let conditions = NetworkConditions {
+            delay: Duration::from_millis(1),
+            jitter: Duration::from_millis(2),
+            packet_loss: 0.2,
+            duplicate_chance: 0.1,
+            reorder_chance: 0.1,
+        };
+        let received = send_packets(
+            &LoopbackNetwork::new(conditions.clone(), 7),
+            Delivery::Unreliable,
+        );
+        let mut unique = received.clone();
+        unique.sort();
+        unique.dedup();
+        assert!(unique.len() < 100, "some packets are lost");
+        assert!(received.len() > unique.len(), "some packets are duplicated");
+        assert!(
+            received.windows(2).any(|pair| pair[0] > pair[1]),
+            "some packets are out of order"
+        );
+
+        // the same seed gives the same packets in the same order
+        let again = send_packets(
+            &LoopbackNetwork::new(conditions.clone(), 7),
+            Delivery::Unreliable,
+        );
+        assert_eq!(again, received);
+
+        // reliable packets all arrive exactly once
+        let mut reliable = send_packets(&LoopbackNetwork::new(conditions, 7), Delivery::Reliable);
+        reliable.sort();
+        assert_eq!(reliable, (0..100).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_poisoned_lock() {
+        let network = LoopbackNetwork::new(NetworkConditions::default(), 0);
+        let (mut local, _remote) = network.connect().unwrap();
+        let simulator = network.simulator.clone();
+        let _ = std::thread::spawn(move || {
+            let _simulator = simulator.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+
+        assert!(network.connect().is_err());
+        assert!(network
+            .set_conditions(NetworkConditions::default())
+            .is_err());
+        assert!(local.send(vec![1], Delivery::Unreliable).is_err());
+        assert!(local.poll().is_empty());
+    }
+}
@@ -1,12 +1,124 @@
+use crate::{
+    network::codec::EncodedInputs, sync::ConnectionStatus, FrameIndex, FrameSize, GameInput,
+};
 use serde::{Deserialize, Serialize};
 
+/// Messages sent between peers, based on GGPO's UdpMsg
 #[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
-pub enum NetworkMessage {
-    Input(String),
+pub enum NetworkMessage<T: GameInput> {
+    /// Sent while synchronizing, the peer should reply with the same random
+    /// value
+    SyncRequest {
+        random_request: u32,
+    },
+    SyncReply {
+        random_reply: u32,
+    },
+    /// A run of sequential inputs starting at `start_frame`
+    Input {
+        start_frame: FrameSize,
+        /// Last frame the sender received from us
+        ack_frame: FrameIndex,
+        /// The sender's view of every player so disconnects can be agreed on
+        connect_status: Vec<ConnectionStatus>,
+        inputs: EncodedInputs<T>,
+    },
+    /// Sent when there are no inputs to send but we still need to tell the
+    /// peer which inputs we got
+    InputAck {
+        ack_frame: FrameSize,
+    },
+    /// Sent periodically so the peer can measure ping and how far ahead it is
+    QualityReport {
+        /// How many frames the sender is ahead of the receiver
+        frame_advantage: i32,
+        /// Send time in milliseconds, only meaningful to the sender
+        ping: u64,
+    },
+    /// Reply to a QualityReport with its ping timestamp
+    QualityReply {
+        pong: u64,
+    },
+    /// Sent when nothing else has been sent for a while so the peer knows we
+    /// are still here
+    KeepAlive,
+    /// Checksum of the game state for a confirmed frame, used to detect
+    /// desyncs
+    Checksum {
+        frame: FrameSize,
+        checksum: u64,
+    },
+    /// The frame delay the sender wants for the adjustment round starting on
+    /// `frame`
+    FrameDelay {
+        frame: FrameSize,
+        delay: FrameSize,
+    },
 }
 
-impl NetworkMessage {
-    pub fn make_input(input: &str) -> NetworkMessage {
-        NetworkMessage::Input(String::from(input))
+impl<T: GameInput> NetworkMessage<T> {
+    pub fn make_input(
+        start_frame: FrameSize,
+        ack_frame: FrameIndex,
+        connect_status: Vec<ConnectionStatus>,
+        inputs: EncodedInputs<T>,
+    ) -> NetworkMessage<T> {
+        NetworkMessage::Input {
+            start_frame,
+            ack_frame,
+            connect_status,
+            inputs,
+        }
+    }
+
+    /// `ping` is the time it was sent in milliseconds, the peer only sends
+    /// it back so it can be on any clock
+    pub fn make_quality_report(frame_advantage: i32, ping: u64) -> NetworkMessage<T> {
+        NetworkMessage::QualityReport {
+            frame_advantage,
+            ping,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::codec::BincodeCodec;
+    use bincode::{deserialize, serialize};
+
+    fn round_trip(msg: NetworkMessage<u16>) {
+        let bytes = serialize(&msg).unwrap();
+        assert_eq!(deserialize::<NetworkMessage<u16>>(&bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(NetworkMessage::SyncRequest { random_request: 7 });
+        round_trip(NetworkMessage::SyncReply { random_reply: 7 });
+        round_trip(NetworkMessage::make_input(
+            3,
+            Some(1),
+            vec![
+                ConnectionStatus::default(),
+                ConnectionStatus {
+                    disconnected: true,
+                    last_frame: Some(2),
+                },
+            ],
+            EncodedInputs::encode(&BincodeCodec, &[1, 2]).unwrap(),
+        ));
+        round_trip(NetworkMessage::InputAck { ack_frame: 4 });
+        round_trip(NetworkMessage::make_quality_report(-2, 100));
+        round_trip(NetworkMessage::QualityReply { pong: 12 });
+        round_trip(NetworkMessage::KeepAlive);
+        round_trip(NetworkMessage::Checksum {
+            frame: 60,
+            checksum: u64::max_value(),
+        });
+        round_trip(NetworkMessage::FrameDelay {
+            frame: 120,
+            delay: 3,
+        });
     }
 }
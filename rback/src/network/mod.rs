@@ -1,2 +1,8 @@
+pub mod clock;
+pub mod codec;
+pub mod loopback;
 pub mod message;
+pub mod protocol;
+pub mod shared_socket;
+pub mod transport;
 pub mod udp;
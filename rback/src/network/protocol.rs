@@ -0,0 +1,896 @@
+use crate::{
+    error::NetworkError,
+    game_input_frame::ConfirmedInput,
+    input_queue::INPUT_QUEUE_LENGTH,
+    network::{
+        codec::{BincodeCodec, EncodedInputs, InputCodec},
+        message::NetworkMessage,
+        udp::{NetworkEvent, NetworkHandler},
+    },
+    sync::ConnectionStatus,
+    time_sync::TimeSync,
+    FrameIndex, FrameSize, NetworkInput, PlayerHandle,
+};
+use log::{debug, info};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Settings for the connection to a single peer
+#[derive(Debug, Clone)]
+pub struct ProtocolConfig {
+    /// Number of sync round trips needed before the match can start
+    pub num_sync_packets: u32,
+    /// How long to wait for a sync reply before sending another request
+    pub sync_retry_interval: Duration,
+    /// Resend unacknowledged inputs if we have not heard from the peer in
+    /// this long
+    pub running_retry_interval: Duration,
+    /// How often to measure ping and frame advantage
+    pub quality_report_interval: Duration,
+    /// Send a keep alive if nothing else was sent in this long
+    pub keep_alive_interval: Duration,
+    /// Tell the game the connection was interrupted if nothing was received
+    /// in this long
+    pub disconnect_notify_start: Duration,
+    /// Disconnect if nothing was received in this long
+    pub disconnect_timeout: Duration,
+}
+
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        // https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/network/udp_proto.cpp#L12
+        Self {
+            num_sync_packets: 5,
+            sync_retry_interval: Duration::from_millis(500),
+            running_retry_interval: Duration::from_millis(200),
+            quality_report_interval: Duration::from_millis(1000),
+            keep_alive_interval: Duration::from_millis(200),
+            disconnect_notify_start: Duration::from_millis(750),
+            disconnect_timeout: Duration::from_millis(5000),
+        }
+    }
+}
+
+/// Connection quality to a peer for showing in game, similar to GGPO's
+/// GGPONetworkStats
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NetworkStats {
+    /// Round trip time in milliseconds
+    pub ping: u64,
+    /// Percent of quality reports that never got a reply
+    pub packet_loss: f32,
+    /// Inputs sent that the peer has not acknowledged yet
+    pub send_queue_len: usize,
+    /// Average kilobits per second sent to the peer
+    pub kbps_sent: u32,
+    /// Packets from the peer dropped because they were too large or could
+    /// not be deserialized
+    pub dropped_packets: u64,
+    /// How many frames the peer is ahead of us
+    pub local_frames_behind: i32,
+    /// How many frames we are ahead of the peer according to them
+    pub remote_frames_behind: i32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolState {
+    /// Doing sync round trips with the peer before the match starts
+    Syncing {
+        roundtrips_remaining: u32,
+        /// Random value the peer has to reply with
+        random: u32,
+        last_request_sent: Option<Duration>,
+    },
+    Running,
+    /// Running but we have not heard from the peer in a while
+    Interrupted,
+    Disconnected,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolEvent<T: NetworkInput> {
+    /// Got the first sync reply from the peer
+    Connected,
+    Synchronizing {
+        total: u32,
+        count: u32,
+    },
+    Synchronized,
+    /// Next input from the peer, these are given in frame order
+    Input(ConfirmedInput<T>),
+    /// Have not heard from the peer in a while, they will be disconnected
+    /// if nothing arrives before the timeout
+    NetworkInterrupted {
+        disconnect_timeout: Duration,
+    },
+    /// Heard from the peer again after being interrupted
+    NetworkResumed,
+    /// The peer's checksum for a confirmed frame
+    Checksum {
+        frame: FrameSize,
+        checksum: u64,
+    },
+    /// The peer's proposed frame delay for an adjustment round
+    FrameDelay {
+        frame: FrameSize,
+        delay: FrameSize,
+    },
+    Disconnected,
+}
+
+/// Connection to a single peer, similar to GGPO's UdpProtocol
+pub struct PeerProtocol<T: NetworkInput> {
+    handler: NetworkHandler,
+    config: ProtocolConfig,
+    state: ProtocolState,
+    /// Frame of the last input given out in an event
+    last_received_frame: FrameIndex,
+    /// Inputs that arrived before the inputs for the frames preceding them
+    pending_inputs: BTreeMap<FrameSize, ConfirmedInput<T>>,
+    /// Local inputs the peer has not acknowledged yet, these are all sent in
+    /// every input message so a dropped packet never delays the peer
+    pending_output: VecDeque<(FrameSize, T)>,
+    /// Times are on the handler's clock
+    last_input_received: Duration,
+    /// Last time any message arrived from the peer
+    last_received: Duration,
+    /// Our view of every player, sent with our inputs
+    local_connect_status: Vec<ConnectionStatus>,
+    /// The peer's view of every player
+    peer_connect_status: Vec<ConnectionStatus>,
+    /// Packs inputs into input messages
+    codec: Arc<dyn InputCodec<T>>,
+    /// How many frames the peer is ahead of us
+    local_frame_advantage: i32,
+    /// How many frames we are ahead of the peer, from their last quality
+    /// report
+    remote_frame_advantage: i32,
+    /// Round trip time in milliseconds from the last quality reply
+    round_trip_time: u64,
+    last_quality_report: Duration,
+    quality_reports_sent: u32,
+    quality_replies_received: u32,
+    /// Messages from the peer that were dropped because their inputs could
+    /// not be decoded or were for impossible frames
+    malformed_messages: u64,
+    /// Events to give out on the next poll, from sends that disconnected the
+    /// peer or a poll that failed after taking them
+    pending_events: Vec<ProtocolEvent<T>>,
+    time_sync: TimeSync,
+}
+
+impl<T: NetworkInput> PeerProtocol<T> {
+    pub fn new(handler: NetworkHandler, config: ProtocolConfig) -> Self {
+        let now = handler.now();
+        Self {
+            handler,
+            state: Self::initial_state(&config),
+            config,
+            last_received_frame: None,
+            pending_inputs: BTreeMap::new(),
+            pending_output: VecDeque::new(),
+            last_input_received: now,
+            last_received: now,
+            local_connect_status: Vec::new(),
+            peer_connect_status: Vec::new(),
+            codec: Arc::new(BincodeCodec),
+            local_frame_advantage: 0,
+            remote_frame_advantage: 0,
+            round_trip_time: 0,
+            last_quality_report: now,
+            quality_reports_sent: 0,
+            quality_replies_received: 0,
+            malformed_messages: 0,
+            pending_events: Vec::new(),
+            time_sync: TimeSync::new(),
+        }
+    }
+
+    pub fn state(&self) -> &ProtocolState {
+        &self.state
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == ProtocolState::Running
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.state == ProtocolState::Disconnected
+    }
+
+    /// Stop talking to the peer
+    pub fn disconnect(&mut self) {
+        self.state = ProtocolState::Disconnected;
+        self.pending_output.clear();
+        self.handler.close();
+    }
+
+    /// Set our view of the players to send with the next inputs
+    pub fn set_local_connect_status(&mut self, connect_status: &[ConnectionStatus]) {
+        self.local_connect_status.clear();
+        self.local_connect_status.extend_from_slice(connect_status);
+    }
+
+    /// What the peer last told us about a player
+    pub fn peer_connect_status(&self, player: PlayerHandle) -> Option<&ConnectionStatus> {
+        self.peer_connect_status.get(player as usize)
+    }
+
+    pub fn last_received_frame(&self) -> FrameIndex {
+        self.last_received_frame
+    }
+
+    /// Round trip time in milliseconds, 0 until it has been measured
+    pub fn round_trip_time(&self) -> u64 {
+        self.round_trip_time
+    }
+
+    pub fn network_stats(&self) -> NetworkStats {
+        // the last report may still be waiting on its reply
+        let reports = self.quality_reports_sent.saturating_sub(1);
+        let packet_loss = if reports == 0 {
+            0.0
+        } else {
+            let lost = reports.saturating_sub(self.quality_replies_received);
+            lost as f32 / reports as f32 * 100.0
+        };
+        NetworkStats {
+            ping: self.round_trip_time,
+            packet_loss,
+            send_queue_len: self.pending_output.len(),
+            kbps_sent: self.handler.kbps_sent(),
+            dropped_packets: self.handler.dropped_packets() + self.malformed_messages,
+            local_frames_behind: self.local_frame_advantage,
+            remote_frames_behind: self.remote_frame_advantage,
+        }
+    }
+
+    /// Update our frame advantage with the frame we are about to simulate
+    pub fn set_local_frame(&mut self, local_frame: FrameSize) {
+        // https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/network/udp_proto.cpp#L691
+        // estimate the peer's frame as the last one we got from them plus
+        // however many frames they ran while it was in flight
+        let last_received = self.last_received_frame.map_or(-1, |frame| frame as i64);
+        let remote_frame = last_received + (self.round_trip_time as i64 * 60 / 1000);
+        self.local_frame_advantage = (remote_frame - local_frame as i64) as i32;
+    }
+
+    /// Frames we should wait for the peer to catch up
+    pub fn recommend_frame_delay(&self) -> FrameSize {
+        self.time_sync.recommend_frame_wait_duration()
+    }
+
+    fn initial_state(config: &ProtocolConfig) -> ProtocolState {
+        if config.num_sync_packets == 0 {
+            return ProtocolState::Running;
+        }
+        ProtocolState::Syncing {
+            roundtrips_remaining: config.num_sync_packets,
+            random: 0,
+            last_request_sent: None,
+        }
+    }
+
+    /// Changing the config restarts synchronization if it has not finished
+    pub fn set_config(&mut self, config: ProtocolConfig) {
+        if let ProtocolState::Syncing { .. } = self.state {
+            self.state = Self::initial_state(&config);
+        }
+        self.config = config;
+    }
+
+    /// Both peers need to use the same codec
+    pub fn set_codec(&mut self, codec: Arc<dyn InputCodec<T>>) {
+        self.codec = codec;
+    }
+
+    /// Queue an input to be sent on the next poll along with every input
+    /// the peer has not acknowledged. A peer that has not acknowledged
+    /// `INPUT_QUEUE_LENGTH` inputs is too far behind and is disconnected.
+    pub fn send_input(&mut self, input: ConfirmedInput<T>) -> Result<(), NetworkError> {
+        if self.is_disconnected() {
+            return Ok(());
+        }
+        if self.pending_output.len() >= INPUT_QUEUE_LENGTH {
+            info!(
+                "peer has not acknowledged {} inputs, disconnecting",
+                self.pending_output.len()
+            );
+            self.disconnect();
+            self.pending_events.push(ProtocolEvent::Disconnected);
+            return Ok(());
+        }
+        self.time_sync.advance_frame(
+            input.frame,
+            self.local_frame_advantage,
+            self.remote_frame_advantage,
+        );
+        self.pending_output.push_back((input.frame, input.input));
+        self.send_pending_output()
+    }
+
+    /// Send the checksum of our state for a confirmed frame
+    pub fn send_checksum(&mut self, frame: FrameSize, checksum: u64) -> Result<(), NetworkError> {
+        if self.is_disconnected() {
+            return Ok(());
+        }
+        self.handler
+            .queue_msg(&NetworkMessage::<T>::Checksum { frame, checksum })
+    }
+
+    /// Send our proposed frame delay for the adjustment round starting on
+    /// `frame`
+    pub fn send_frame_delay(
+        &mut self,
+        frame: FrameSize,
+        delay: FrameSize,
+    ) -> Result<(), NetworkError> {
+        if self.is_disconnected() {
+            return Ok(());
+        }
+        self.handler
+            .queue_msg(&NetworkMessage::<T>::FrameDelay { frame, delay })
+    }
+
+    fn send_pending_output(&mut self) -> Result<(), NetworkError> {
+        let start_frame = match self.pending_output.front() {
+            Some((frame, _)) => *frame,
+            None => return Ok(()),
+        };
+        let inputs = EncodedInputs::encode(
+            self.codec.as_ref(),
+            self.pending_output.iter().map(|(_, input)| input),
+        )
+        .ok_or(NetworkError::InputEncoding)?;
+        let msg = NetworkMessage::make_input(
+            start_frame,
+            self.last_received_frame,
+            self.local_connect_status.clone(),
+            inputs,
+        );
+        self.handler.queue_unreliable_msg(&msg)
+    }
+
+    /// The peer has every input up to and including this frame
+    fn on_ack(&mut self, ack_frame: FrameSize) {
+        while let Some((frame, _)) = self.pending_output.front() {
+            if *frame > ack_frame {
+                break;
+            }
+            self.pending_output.pop_front();
+        }
+    }
+
+    /// Send anything queued, handle everything received from the peer and
+    /// return what happened
+    pub fn poll(&mut self) -> Result<Vec<ProtocolEvent<T>>, NetworkError> {
+        let mut events = std::mem::take(&mut self.pending_events);
+        match self.poll_events(&mut events) {
+            Ok(()) => Ok(events),
+            Err(error) => {
+                // keep what already happened for the next poll
+                self.pending_events = events;
+                Err(error)
+            }
+        }
+    }
+
+    fn poll_events(&mut self, events: &mut Vec<ProtocolEvent<T>>) -> Result<(), NetworkError> {
+        if self.state == ProtocolState::Disconnected {
+            return Ok(());
+        }
+
+        self.send_sync_request_if_needed()?;
+        self.handler.empty_msg_queue();
+
+        // the messages are gone from the transport once polled so every one
+        // is handled even if replying to one fails
+        let mut result = Ok(());
+        for network_event in self.handler.poll_events::<T>() {
+            match network_event {
+                NetworkEvent::Message(msg) => {
+                    self.last_received = self.handler.now();
+                    if self.state == ProtocolState::Interrupted {
+                        info!("peer connection resumed");
+                        self.state = ProtocolState::Running;
+                        events.push(ProtocolEvent::NetworkResumed);
+                    }
+                    if let Err(error) = self.on_message(msg, events) {
+                        if result.is_ok() {
+                            result = Err(error);
+                        }
+                    }
+                }
+                NetworkEvent::Connected => {}
+                NetworkEvent::TimedOut => {
+                    info!("peer timed out, disconnecting");
+                    // inputs that arrived before the timeout are still valid
+                    self.take_ready_inputs(events);
+                    self.disconnect();
+                    events.push(ProtocolEvent::Disconnected);
+                    return result;
+                }
+            }
+        }
+        let received_inputs = self.take_ready_inputs(events);
+        result?;
+
+        if self.is_running() || self.state == ProtocolState::Interrupted {
+            let now = self.handler.now();
+            if received_inputs {
+                self.last_input_received = now;
+                // if we have inputs to send they carry the ack
+                if self.pending_output.is_empty() {
+                    self.send_input_ack()?;
+                }
+            } else if now - self.last_input_received > self.config.running_retry_interval {
+                // our inputs or their acks might have been dropped
+                self.last_input_received = now;
+                self.send_pending_output()?;
+                self.send_input_ack()?;
+            }
+            if now - self.last_quality_report >= self.config.quality_report_interval {
+                self.last_quality_report = now;
+                self.quality_reports_sent += 1;
+                let msg = NetworkMessage::<T>::make_quality_report(
+                    self.local_frame_advantage,
+                    now.as_millis() as u64,
+                );
+                self.handler.queue_unreliable_msg(&msg)?;
+            }
+            if self.handler.now() - self.handler.last_sent() >= self.config.keep_alive_interval {
+                self.handler
+                    .queue_unreliable_msg(&NetworkMessage::<T>::KeepAlive)?;
+            }
+            self.check_disconnect(events);
+        }
+
+        // send anything generated by the messages we just handled
+        self.handler.empty_msg_queue();
+        Ok(())
+    }
+
+    fn check_disconnect(&mut self, events: &mut Vec<ProtocolEvent<T>>) {
+        let elapsed = self.handler.now() - self.last_received;
+        if elapsed >= self.config.disconnect_timeout {
+            info!("no messages from peer in {:?}, disconnecting", elapsed);
+            self.disconnect();
+            events.push(ProtocolEvent::Disconnected);
+        } else if self.is_running() && elapsed >= self.config.disconnect_notify_start {
+            info!(
+                "no messages from peer in {:?}, connection interrupted",
+                elapsed
+            );
+            self.state = ProtocolState::Interrupted;
+            events.push(ProtocolEvent::NetworkInterrupted {
+                disconnect_timeout: self.config.disconnect_timeout - elapsed,
+            });
+        }
+    }
+
+    fn send_sync_request_if_needed(&mut self) -> Result<(), NetworkError> {
+        let retry_interval = self.config.sync_retry_interval;
+        let now = self.handler.now();
+        let random = match &mut self.state {
+            ProtocolState::Syncing {
+                random,
+                last_request_sent,
+                ..
+            } => {
+                let should_send =
+                    last_request_sent.map_or(true, |sent| now - sent > retry_interval);
+                if !should_send {
+                    return Ok(());
+                }
+                *random = rand::random();
+                *last_request_sent = Some(now);
+                *random
+            }
+            _ => return Ok(()),
+        };
+        self.handler.queue_msg(&NetworkMessage::<T>::SyncRequest {
+            random_request: random,
+        })
+    }
+
+    fn on_message(
+        &mut self,
+        msg: NetworkMessage<T>,
+        events: &mut Vec<ProtocolEvent<T>>,
+    ) -> Result<(), NetworkError> {
+        match msg {
+            NetworkMessage::SyncRequest { random_request } => {
+                self.handler.queue_msg(&NetworkMessage::<T>::SyncReply {
+                    random_reply: random_request,
+                })?;
+            }
+            NetworkMessage::SyncReply { random_reply } => {
+                self.on_sync_reply(random_reply, events)?
+            }
+            NetworkMessage::Input {
+                start_frame,
+                ack_frame,
+                connect_status,
+                inputs,
+            } => {
+                let inputs = match inputs.decode(self.codec.as_ref()) {
+                    Some(inputs) => inputs,
+                    None => {
+                        debug!("dropping input message that could not be decoded");
+                        self.malformed_messages += 1;
+                        return Ok(());
+                    }
+                };
+                // the peer never has more than a queue's worth of inputs we
+                // have not received
+                let max_frame = self
+                    .last_received_frame
+                    .map_or(INPUT_QUEUE_LENGTH as FrameSize - 1, |last| {
+                        last.saturating_add(INPUT_QUEUE_LENGTH as FrameSize)
+                    });
+                let in_range = start_frame
+                    .checked_add(inputs.len() as FrameSize)
+                    .map_or(false, |end| inputs.is_empty() || end - 1 <= max_frame);
+                if !in_range {
+                    debug!(
+                        "dropping {} inputs from frame {} which is past frame {}",
+                        inputs.len(),
+                        start_frame,
+                        max_frame
+                    );
+                    self.malformed_messages += 1;
+                    return Ok(());
+                }
+                // only trusted once the inputs check out so a malformed
+                // message can't disconnect anyone
+                if let Some(ack_frame) = ack_frame {
+                    self.on_ack(ack_frame);
+                }
+                self.update_peer_connect_status(&connect_status);
+                for (frame, input) in (start_frame..).zip(inputs) {
+                    // drop inputs we already handed out
+                    if self.last_received_frame.map_or(true, |last| frame > last) {
+                        self.pending_inputs
+                            .insert(frame, ConfirmedInput::new(input, frame));
+                    }
+                }
+            }
+            NetworkMessage::InputAck { ack_frame } => self.on_ack(ack_frame),
+            NetworkMessage::QualityReport {
+                frame_advantage,
+                ping,
+            } => {
+                self.remote_frame_advantage = frame_advantage;
+                self.handler
+                    .queue_unreliable_msg(&NetworkMessage::<T>::QualityReply { pong: ping })?;
+            }
+            NetworkMessage::QualityReply { pong } => {
+                self.quality_replies_received += 1;
+                let now = self.handler.now().as_millis() as u64;
+                self.round_trip_time = now.saturating_sub(pong);
+            }
+            NetworkMessage::Checksum { frame, checksum } => {
+                events.push(ProtocolEvent::Checksum { frame, checksum });
+            }
+            NetworkMessage::FrameDelay { frame, delay } => {
+                events.push(ProtocolEvent::FrameDelay { frame, delay });
+            }
+            NetworkMessage::KeepAlive => {}
+        }
+        Ok(())
+    }
+
+    /// Messages can arrive out of order so once a player is disconnected they
+    /// stay that way
+    // https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/network/udp_proto.cpp#L473
+    fn update_peer_connect_status(&mut self, connect_status: &[ConnectionStatus]) {
+        if self.peer_connect_status.len() < connect_status.len() {
+            self.peer_connect_status
+                .resize(connect_status.len(), ConnectionStatus::default());
+        }
+        for (status, new_status) in self.peer_connect_status.iter_mut().zip(connect_status) {
+            status.disconnected |= new_status.disconnected;
+            status.last_frame = status.last_frame.max(new_status.last_frame);
+        }
+    }
+
+    fn send_input_ack(&mut self) -> Result<(), NetworkError> {
+        match self.last_received_frame {
+            Some(ack_frame) => self
+                .handler
+                .queue_unreliable_msg(&NetworkMessage::<T>::InputAck { ack_frame }),
+            None => Ok(()),
+        }
+    }
+
+    fn on_sync_reply(
+        &mut self,
+        random_reply: u32,
+        events: &mut Vec<ProtocolEvent<T>>,
+    ) -> Result<(), NetworkError> {
+        let total = self.config.num_sync_packets;
+        let remaining = match &mut self.state {
+            ProtocolState::Syncing {
+                roundtrips_remaining,
+                random,
+                last_request_sent,
+            } if *random == random_reply => {
+                if *roundtrips_remaining == total {
+                    events.push(ProtocolEvent::Connected);
+                }
+                *roundtrips_remaining = roundtrips_remaining.saturating_sub(1);
+                // send the next request right away
+                *last_request_sent = None;
+                *roundtrips_remaining
+            }
+            // reply to an old request or we are already synchronized
+            _ => return Ok(()),
+        };
+
+        if remaining == 0 {
+            info!("synchronized with peer");
+            self.state = ProtocolState::Running;
+            events.push(ProtocolEvent::Synchronized);
+        } else {
+            events.push(ProtocolEvent::Synchronizing {
+                total,
+                count: total - remaining,
+            });
+            self.send_sync_request_if_needed()?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if any inputs were ready
+    fn take_ready_inputs(&mut self, events: &mut Vec<ProtocolEvent<T>>) -> bool {
+        let last_received_frame = self.last_received_frame;
+        loop {
+            let next_frame = self.last_received_frame.map_or(0, |last| last + 1);
+            match self.pending_inputs.remove(&next_frame) {
+                Some(input) => {
+                    self.last_received_frame = Some(next_frame);
+                    events.push(ProtocolEvent::Input(input));
+                }
+                None => break,
+            }
+        }
+        self.last_received_frame != last_received_frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{
+        loopback::{LoopbackNetwork, NetworkConditions},
+        transport::{Delivery, Transport, TransportEvent},
+    };
+
+    /// Transport that gives out the same events on its first poll and sends
+    /// nowhere
+    struct ScriptedTransport(Vec<TransportEvent>);
+
+    impl Transport for ScriptedTransport {
+        fn send(&mut self, _payload: Vec<u8>, _delivery: Delivery) -> Result<(), NetworkError> {
+            Ok(())
+        }
+
+        fn poll(&mut self) -> Vec<TransportEvent> {
+            std::mem::take(&mut self.0)
+        }
+    }
+
+    /// Handlers connected to each other over a perfect loopback network
+    fn handlers() -> (NetworkHandler, NetworkHandler) {
+        let (local, remote) = LoopbackNetwork::new(NetworkConditions::default(), 0)
+            .connect()
+            .unwrap();
+        (
+            NetworkHandler::with_transport(local),
+            NetworkHandler::with_transport(remote),
+        )
+    }
+
+    #[test]
+    fn test_send_unacked_inputs() -> Result<(), NetworkError> {
+        let config = ProtocolConfig {
+            num_sync_packets: 0,
+            ..ProtocolConfig::default()
+        };
+        let (local_handler, remote_handler) = handlers();
+        let mut local: PeerProtocol<u8> = PeerProtocol::new(local_handler, config.clone());
+        let mut remote: PeerProtocol<u8> = PeerProtocol::new(remote_handler, config);
+
+        // every message has all the inputs so far since nothing was acked
+        for frame in 0..3 {
+            local.send_input((frame, frame as FrameSize).into())?;
+        }
+        assert_eq!(local.pending_output.len(), 3);
+        local.poll()?;
+
+        // duplicate inputs are only given out once
+        assert_eq!(
+            remote.poll()?,
+            vec![
+                ProtocolEvent::Input((0, 0).into()),
+                ProtocolEvent::Input((1, 1).into()),
+                ProtocolEvent::Input((2, 2).into()),
+            ]
+        );
+
+        // remote had nothing to send so it acked the inputs
+        local.poll()?;
+        assert!(local.pending_output.is_empty());
+
+        local.send_input((3, 3).into())?;
+        assert_eq!(local.pending_output.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quality_report() -> Result<(), NetworkError> {
+        let config = ProtocolConfig {
+            num_sync_packets: 0,
+            quality_report_interval: Duration::from_millis(0),
+            ..ProtocolConfig::default()
+        };
+        let (local_handler, remote_handler) = handlers();
+        let mut local: PeerProtocol<u8> = PeerProtocol::new(local_handler, config.clone());
+        let mut remote: PeerProtocol<u8> = PeerProtocol::new(remote_handler, config);
+
+        // nothing received from the remote so it is 6 frames behind us
+        local.set_local_frame(5);
+        assert_eq!(local.local_frame_advantage, -6);
+        local.poll()?;
+        remote.poll()?;
+        assert_eq!(remote.remote_frame_advantage, -6);
+
+        local.send_input((1, 0).into())?;
+        let stats = local.network_stats();
+        assert_eq!(stats.send_queue_len, 1);
+        assert_eq!(stats.local_frames_behind, -6);
+        assert!(local.handler.packets_sent() > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disconnect() -> Result<(), NetworkError> {
+        let config = ProtocolConfig {
+            num_sync_packets: 0,
+            keep_alive_interval: Duration::from_millis(0),
+            disconnect_notify_start: Duration::from_millis(50),
+            disconnect_timeout: Duration::from_millis(150),
+            ..ProtocolConfig::default()
+        };
+        let network = LoopbackNetwork::new(NetworkConditions::default(), 0);
+        let (local_transport, remote_transport) = network.connect()?;
+        let mut local: PeerProtocol<u8> = PeerProtocol::new(
+            NetworkHandler::with_transport(local_transport),
+            config.clone(),
+        );
+        let mut remote: PeerProtocol<u8> =
+            PeerProtocol::new(NetworkHandler::with_transport(remote_transport), config);
+
+        assert_eq!(local.poll()?, vec![]);
+        network.advance(Duration::from_millis(60));
+        let events = local.poll()?;
+        assert!(matches!(
+            events.as_slice(),
+            [ProtocolEvent::NetworkInterrupted { .. }]
+        ));
+        assert_eq!(local.state(), &ProtocolState::Interrupted);
+
+        // any message from the remote, like a keep alive, resumes it
+        remote.poll()?;
+        assert_eq!(local.poll()?, vec![ProtocolEvent::NetworkResumed]);
+        assert!(local.is_running());
+
+        network.advance(Duration::from_millis(160));
+        assert_eq!(local.poll()?, vec![ProtocolEvent::Disconnected]);
+        assert!(local.is_disconnected());
+        assert_eq!(local.poll()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inputs_before_timeout() -> Result<(), NetworkError> {
+        let msg = NetworkMessage::make_input(
+            0,
+            None,
+            vec![],
+            EncodedInputs::encode(&BincodeCodec, &[1u8]).unwrap(),
+        );
+        let transport = ScriptedTransport(vec![
+            TransportEvent::Datagram(bincode::serialize(&msg).unwrap()),
+            TransportEvent::TimedOut,
+        ]);
+        let mut local: PeerProtocol<u8> = PeerProtocol::new(
+            NetworkHandler::with_transport(transport),
+            ProtocolConfig {
+                num_sync_packets: 0,
+                ..ProtocolConfig::default()
+            },
+        );
+
+        // the input arrived before the timeout so it is still handed out
+        assert_eq!(
+            local.poll()?,
+            vec![
+                ProtocolEvent::Input((1, 0).into()),
+                ProtocolEvent::Disconnected
+            ]
+        );
+        assert!(local.is_disconnected());
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_inputs() -> Result<(), NetworkError> {
+        let (local, mut remote) =
+            LoopbackNetwork::new(NetworkConditions::default(), 0).connect()?;
+        let mut local: PeerProtocol<u8> = PeerProtocol::new(
+            NetworkHandler::with_transport(local),
+            ProtocolConfig {
+                num_sync_packets: 0,
+                ..ProtocolConfig::default()
+            },
+        );
+        let mut send_inputs = |start_frame: FrameSize, inputs: &[u8]| {
+            // claims everyone left so we can tell if a malformed message is
+            // trusted
+            let connect_status = if start_frame == 0 {
+                vec![]
+            } else {
+                vec![ConnectionStatus {
+                    disconnected: true,
+                    last_frame: Some(0),
+                }]
+            };
+            let msg = NetworkMessage::make_input(
+                start_frame,
+                None,
+                connect_status,
+                EncodedInputs::encode(&BincodeCodec, inputs).unwrap(),
+            );
+            remote
+                .send(bincode::serialize(&msg).unwrap(), Delivery::Unreliable)
+                .unwrap();
+        };
+        // the frames after the last one would overflow
+        send_inputs(FrameSize::max_value(), &[1, 2]);
+        // too far past the inputs we have
+        send_inputs(INPUT_QUEUE_LENGTH as FrameSize, &[1]);
+        send_inputs(0, &[1]);
+
+        assert_eq!(local.poll()?, vec![ProtocolEvent::Input((1, 0).into())]);
+        assert!(local.pending_inputs.is_empty());
+        assert_eq!(local.network_stats().dropped_packets, 2);
+        assert_eq!(local.peer_connect_status(0), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_peer_too_far_behind() -> Result<(), NetworkError> {
+        // the remote never polls so it never acks anything
+        let (local, _remote) = LoopbackNetwork::new(NetworkConditions::default(), 0).connect()?;
+        let mut local: PeerProtocol<u8> = PeerProtocol::new(
+            NetworkHandler::with_transport(local),
+            ProtocolConfig {
+                num_sync_packets: 0,
+                ..ProtocolConfig::default()
+            },
+        );
+        for frame in 0..INPUT_QUEUE_LENGTH as FrameSize {
+            local.send_input((0, frame).into())?;
+        }
+        assert!(local.is_running());
+        local.send_input((0, INPUT_QUEUE_LENGTH as FrameSize).into())?;
+        assert!(local.is_disconnected());
+        assert_eq!(local.poll()?, vec![ProtocolEvent::Disconnected]);
+        assert_eq!(local.poll()?, vec![]);
+        Ok(())
+    }
+}
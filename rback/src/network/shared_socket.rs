@@ -0,0 +1,361 @@
+use crate::{
+    error::NetworkError,
+    network::transport::{Delivery, Transport, TransportEvent},
+    PlayerHandle,
+};
+use laminar::{Packet, Socket, SocketEvent};
+use log::debug;
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
+};
+
+/// Most events kept for a peer between polls, anything received after that
+/// is dropped until the peer's transport is polled again
+const MAX_INBOX_LEN: usize = 256;
+
+/// Who is on the other end of a peer's address
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerKind {
+    Player(PlayerHandle),
+    Spectator,
+}
+
+struct Peer {
+    kind: PeerKind,
+    /// Events received for the peer that its transport has not polled yet
+    inbox: Vec<TransportEvent>,
+}
+
+struct SocketHub {
+    socket: Socket,
+    peers: HashMap<SocketAddr, Peer>,
+    rejected_packets: u64,
+}
+
+impl SocketHub {
+    /// Send anything queued and sort everything received into the inbox of
+    /// the peer it came from
+    fn poll(&mut self) {
+        self.socket.manual_poll(Instant::now());
+        while let Some(event) = self.socket.recv() {
+            let (addr, event) = match event {
+                SocketEvent::Packet(packet) => (
+                    packet.addr(),
+                    TransportEvent::Datagram(packet.payload().to_vec()),
+                ),
+                SocketEvent::Connect(addr) => (addr, TransportEvent::Connected),
+                SocketEvent::Timeout(addr) => (addr, TransportEvent::TimedOut),
+            };
+            match self.peers.get_mut(&addr) {
+                Some(peer) if peer.inbox.len() < MAX_INBOX_LEN => peer.inbox.push(event),
+                Some(_) => {
+                    debug!("inbox of {} is full, dropping {:?}", addr, event);
+                    if let TransportEvent::Datagram(_) = event {
+                        self.rejected_packets += 1;
+                    }
+                }
+                None => {
+                    debug!("rejecting {:?} from unknown address {}", event, addr);
+                    if let TransportEvent::Datagram(_) = event {
+                        self.rejected_packets += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Lock the hub, a peer that panicked while holding it gives an error
+/// instead of panicking every other peer
+fn lock(hub: &Mutex<SocketHub>) -> Result<MutexGuard<SocketHub>, NetworkError> {
+    hub.lock()
+        .map_err(|_| io::Error::new(ErrorKind::Other, "shared socket lock was poisoned").into())
+}
+
+/// One laminar socket shared by every peer of a session
+///
+/// Each player or spectator added gets a `PeerTransport` that only sends to
+/// and receives from their address. Packets from addresses that were not
+/// added are dropped. A peer is removed when its transport is dropped or
+/// its session disconnects it.
+#[derive(Clone)]
+pub struct SharedSocket {
+    hub: Arc<Mutex<SocketHub>>,
+}
+
+impl SharedSocket {
+    pub fn bind(local_addr: SocketAddr) -> Result<Self, NetworkError> {
+        let socket = Socket::bind(local_addr)?;
+        Ok(Self::new(socket))
+    }
+
+    /// Use an already bound socket
+    pub fn new(socket: Socket) -> Self {
+        Self {
+            hub: Arc::new(Mutex::new(SocketHub {
+                socket,
+                peers: HashMap::new(),
+                rejected_packets: 0,
+            })),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(lock(&self.hub)?.socket.local_addr()?)
+    }
+
+    /// Transport for a remote player at this address
+    pub fn player(
+        &self,
+        handle: PlayerHandle,
+        remote_addr: SocketAddr,
+    ) -> Result<PeerTransport, NetworkError> {
+        self.add_peer(PeerKind::Player(handle), remote_addr)
+    }
+
+    /// Transport for a spectator at this address
+    pub fn spectator(&self, remote_addr: SocketAddr) -> Result<PeerTransport, NetworkError> {
+        self.add_peer(PeerKind::Spectator, remote_addr)
+    }
+
+    fn add_peer(
+        &self,
+        kind: PeerKind,
+        remote_addr: SocketAddr,
+    ) -> Result<PeerTransport, NetworkError> {
+        let mut hub = lock(&self.hub)?;
+        if hub.peers.contains_key(&remote_addr) {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} was already added", remote_addr),
+            )
+            .into());
+        }
+        if let PeerKind::Player(handle) = kind {
+            if hub.peers.values().any(|peer| peer.kind == kind) {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("player {} was already added", handle),
+                )
+                .into());
+            }
+        }
+        hub.peers.insert(
+            remote_addr,
+            Peer {
+                kind,
+                inbox: Vec::new(),
+            },
+        );
+        Ok(PeerTransport {
+            hub: self.hub.clone(),
+            remote_addr,
+        })
+    }
+
+    /// Every peer's address and who they are
+    pub fn peers(&self) -> Result<Vec<(SocketAddr, PeerKind)>, NetworkError> {
+        let hub = lock(&self.hub)?;
+        Ok(hub
+            .peers
+            .iter()
+            .map(|(addr, peer)| (*addr, peer.kind))
+            .collect())
+    }
+
+    /// Number of packets dropped because they came from an unknown address
+    /// or a peer that had too many waiting to be polled
+    pub fn rejected_packets(&self) -> Result<u64, NetworkError> {
+        Ok(lock(&self.hub)?.rejected_packets)
+    }
+}
+
+/// Connection to a single peer through a `SharedSocket`, the peer is removed
+/// from the socket when this is dropped
+pub struct PeerTransport {
+    hub: Arc<Mutex<SocketHub>>,
+    remote_addr: SocketAddr,
+}
+
+impl PeerTransport {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    fn remove(&mut self) {
+        if let Ok(mut hub) = lock(&self.hub) {
+            hub.peers.remove(&self.remote_addr);
+        }
+    }
+}
+
+impl Transport for PeerTransport {
+    fn send(&mut self, payload: Vec<u8>, delivery: Delivery) -> Result<(), NetworkError> {
+        let packet = match delivery {
+            Delivery::Reliable => Packet::reliable_unordered(self.remote_addr, payload),
+            Delivery::Unreliable => Packet::unreliable(self.remote_addr, payload),
+        };
+        Ok(lock(&self.hub)?.socket.send(packet)?)
+    }
+
+    fn flush(&mut self) {
+        if let Ok(mut hub) = lock(&self.hub) {
+            hub.socket.manual_poll(Instant::now())
+        }
+    }
+
+    fn poll(&mut self) -> Vec<TransportEvent> {
+        let mut hub = match lock(&self.hub) {
+            Ok(hub) => hub,
+            Err(error) => {
+                debug!("not polling {}: {}", self.remote_addr, error);
+                return Vec::new();
+            }
+        };
+        hub.poll();
+        match hub.peers.get_mut(&self.remote_addr) {
+            Some(peer) => peer.inbox.drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn close(&mut self) {
+        self.remove();
+    }
+}
+
+impl Drop for PeerTransport {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::transport::LaminarTransport;
+    use std::time::Duration;
+
+    /// Shared socket on any free port and its address
+    fn host() -> Result<(SharedSocket, SocketAddr), NetworkError> {
+        let host = SharedSocket::bind("127.0.0.1:0".parse().unwrap())?;
+        let addr = host.local_addr()?;
+        Ok((host, addr))
+    }
+
+    /// Remote end sending to the host from any free port
+    fn remote(host_addr: SocketAddr) -> Result<(LaminarTransport, SocketAddr), NetworkError> {
+        let transport = LaminarTransport::new(Socket::bind("127.0.0.1:0")?, host_addr);
+        let addr = transport.local_addr()?;
+        Ok((transport, addr))
+    }
+
+    /// Poll until a datagram arrives or give up after a second
+    fn receive(transport: &mut PeerTransport) -> Option<Vec<u8>> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            for event in transport.poll() {
+                if let TransportEvent::Datagram(payload) = event {
+                    return Some(payload);
+                }
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_routes_by_address() -> Result<(), NetworkError> {
+        let (host, host_addr) = host()?;
+        let (mut remote_player, player_addr) = remote(host_addr)?;
+        let (mut remote_spectator, spectator_addr) = remote(host_addr)?;
+        let (mut stranger, stranger_addr) = remote(host_addr)?;
+        let mut player = host.player(1, player_addr)?;
+        let mut spectator = host.spectator(spectator_addr)?;
+        assert!(host.player(1, stranger_addr).is_err());
+        assert!(host.spectator(player_addr).is_err());
+
+        stranger.send(vec![3], Delivery::Unreliable)?;
+        stranger.flush();
+        remote_player.send(vec![1], Delivery::Unreliable)?;
+        remote_player.flush();
+        remote_spectator.send(vec![2], Delivery::Unreliable)?;
+        remote_spectator.flush();
+
+        assert_eq!(receive(&mut player), Some(vec![1]));
+        assert_eq!(receive(&mut spectator), Some(vec![2]));
+        assert_eq!(host.rejected_packets()?, 1);
+
+        // replies go to the peer's own address
+        spectator.send(vec![4], Delivery::Unreliable)?;
+        spectator.flush();
+        let start = Instant::now();
+        let mut received = Vec::new();
+        while received.is_empty() && start.elapsed() < Duration::from_secs(1) {
+            received = remote_spectator.poll();
+        }
+        assert_eq!(received, vec![TransportEvent::Datagram(vec![4])]);
+        assert!(remote_player.poll().is_empty());
+
+        // a dropped peer is forgotten so its traffic is rejected
+        drop(player);
+        assert_eq!(host.peers()?, vec![(spectator_addr, PeerKind::Spectator)]);
+        remote_player.send(vec![5], Delivery::Unreliable)?;
+        remote_player.flush();
+        assert_eq!(receive(&mut spectator), None);
+        assert_eq!(host.rejected_packets()?, 2);
+
+        // so is a disconnected one
+        spectator.close();
+        assert_eq!(host.peers()?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inbox_is_bounded() -> Result<(), NetworkError> {
+        let (host, host_addr) = host()?;
+        let (mut remote_player, player_addr) = remote(host_addr)?;
+        let mut player = host.player(1, player_addr)?;
+        // sent a few at a time so the OS doesn't drop any, the other peers
+        // keep polling the socket while this one doesn't
+        for batch in 0..MAX_INBOX_LEN as u64 / 8 + 2 {
+            for byte in 0..8 {
+                remote_player.send(vec![byte], Delivery::Unreliable)?;
+            }
+            remote_player.flush();
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(1) {
+                let mut hub = lock(&host.hub)?;
+                hub.poll();
+                let received = hub.peers[&player_addr].inbox.len() as u64 + hub.rejected_packets;
+                if received == (batch + 1) * 8 {
+                    break;
+                }
+            }
+        }
+        assert_eq!(host.rejected_packets()?, 16);
+        assert_eq!(player.poll().len(), MAX_INBOX_LEN);
+        Ok(())
+    }
+
+    #[test]
+    fn test_poisoned_lock() -> Result<(), NetworkError> {
+        let (host, host_addr) = host()?;
+        let (_, player_addr) = remote(host_addr)?;
+        let mut player = host.player(1, player_addr)?;
+        let hub = host.hub.clone();
+        let _ = std::thread::spawn(move || {
+            let _hub = hub.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+
+        assert!(host.peers().is_err());
+        assert!(player.send(vec![1], Delivery::Unreliable).is_err());
+        assert!(player.poll().is_empty());
+        Ok(())
+    }
+}
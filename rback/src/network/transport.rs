@@ -0,0 +1,151 @@
+use crate::{error::NetworkError, network::clock::Clock};
+use laminar::{Packet, Socket, SocketEvent};
+use log::debug;
+use std::{net::SocketAddr, sync::Arc, time::Instant};
+
+/// How a datagram should be delivered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    /// Resent until the peer receives it, may arrive out of order
+    Reliable,
+    /// May be dropped or arrive out of order, used for messages we resend
+    /// ourselves until the peer acknowledges them
+    Unreliable,
+}
+
+/// Something a transport received from the peer
+#[derive(Debug, PartialEq)]
+pub enum TransportEvent {
+    Datagram(Vec<u8>),
+    /// Received traffic from the peer for the first time
+    Connected,
+    /// Has not heard from the peer in a while
+    TimedOut,
+}
+
+/// Sends and receives datagrams with a single peer. `NetworkHandler` uses
+/// laminar over UDP by default, implement this to run sessions over a relay,
+/// WebRTC data channels or platform sockets instead.
+///
+/// Transports that can't tell when the peer connects or times out don't
+/// need to give those events, the session's own keep alives detect
+/// disconnects.
+pub trait Transport: Send {
+    /// Queue a datagram to be sent to the peer
+    fn send(&mut self, payload: Vec<u8>, delivery: Delivery) -> Result<(), NetworkError>;
+    /// Send anything queued, transports that send right away don't need to
+    /// do anything
+    fn flush(&mut self) {}
+    /// Send anything queued and return everything received since the last
+    /// poll
+    fn poll(&mut self) -> Vec<TransportEvent>;
+    /// The peer was disconnected and won't be polled again, transports
+    /// holding on to events for it should free them
+    fn close(&mut self) {}
+    /// Clock to run the session's timers on, transports simulating a network
+    /// give their own so time only passes when the simulation says so. The
+    /// system clock is used by default.
+    fn clock(&self) -> Option<Arc<dyn Clock>> {
+        None
+    }
+}
+
+/// laminar socket sending to a single remote address
+pub struct LaminarTransport {
+    socket: Socket,
+    remote_addr: SocketAddr,
+}
+
+impl LaminarTransport {
+    /// Listen on `local_addr` and send to `remote_addr`
+    pub fn bind(local_addr: SocketAddr, remote_addr: SocketAddr) -> Result<Self, NetworkError> {
+        let socket = Socket::bind(local_addr)?;
+        Ok(Self::new(socket, remote_addr))
+    }
+
+    /// Use an already bound socket
+    pub fn new(socket: Socket, remote_addr: SocketAddr) -> Self {
+        Self {
+            socket,
+            remote_addr,
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, NetworkError> {
+        Ok(self.socket.local_addr()?)
+    }
+}
+
+impl Transport for LaminarTransport {
+    fn send(&mut self, payload: Vec<u8>, delivery: Delivery) -> Result<(), NetworkError> {
+        let packet = match delivery {
+            Delivery::Reliable => Packet::reliable_unordered(self.remote_addr, payload),
+            Delivery::Unreliable => Packet::unreliable(self.remote_addr, payload),
+        };
+        Ok(self.socket.send(packet)?)
+    }
+
+    fn flush(&mut self) {
+        self.socket.manual_poll(Instant::now())
+    }
+
+    /// Anything from an address other than the remote is ignored
+    fn poll(&mut self) -> Vec<TransportEvent> {
+        self.socket.manual_poll(Instant::now());
+        let mut events = Vec::new();
+        while let Some(event) = self.socket.recv() {
+            let (addr, event) = match event {
+                SocketEvent::Packet(packet) => (
+                    packet.addr(),
+                    TransportEvent::Datagram(packet.payload().to_vec()),
+                ),
+                SocketEvent::Connect(addr) => (addr, TransportEvent::Connected),
+                SocketEvent::Timeout(addr) => (addr, TransportEvent::TimedOut),
+            };
+            if addr == self.remote_addr {
+                events.push(event);
+            } else {
+                debug!("ignoring {:?} from unknown address {}", event, addr);
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn bind() -> Result<(Socket, SocketAddr), NetworkError> {
+        let socket = Socket::bind("127.0.0.1:0")?;
+        let addr = socket.local_addr()?;
+        Ok((socket, addr))
+    }
+
+    #[test]
+    fn test_ignores_unknown_addresses() -> Result<(), NetworkError> {
+        let (local, local_addr) = bind()?;
+        let (remote, remote_addr) = bind()?;
+        let (stranger, _) = bind()?;
+        let mut local = LaminarTransport::new(local, remote_addr);
+        let mut remote = LaminarTransport::new(remote, local_addr);
+        let mut stranger = LaminarTransport::new(stranger, local_addr);
+
+        stranger.send(vec![1], Delivery::Unreliable)?;
+        stranger.flush();
+        remote.send(vec![2], Delivery::Unreliable)?;
+        remote.flush();
+
+        let start = Instant::now();
+        let mut received = Vec::new();
+        while !received.contains(&TransportEvent::Datagram(vec![2]))
+            && start.elapsed() < Duration::from_secs(1)
+        {
+            received.extend(local.poll());
+        }
+        assert!(received.contains(&TransportEvent::Datagram(vec![2])));
+        assert!(!received.contains(&TransportEvent::Datagram(vec![1])));
+        Ok(())
+    }
+}
@@ -1,89 +1,274 @@
-use crate::network::message::NetworkMessage;
-use bincode::{deserialize, serialize};
-use laminar::{ErrorKind, Packet, Socket, SocketEvent};
-use std::{net::SocketAddr, time::Instant, vec::Vec};
+use crate::{
+    error::NetworkError,
+    network::{
+        clock::{Clock, SystemClock},
+        message::NetworkMessage,
+        transport::{Delivery, LaminarTransport, Transport, TransportEvent},
+    },
+    GameInput, NetworkInput,
+};
+use bincode::{serialize, DefaultOptions, Options};
+use log::{debug, trace};
+use std::{net::SocketAddr, sync::Arc, time::Duration, vec::Vec};
+
+/// Something that happened on the connection to the remote
+#[derive(PartialEq, Debug)]
+pub enum NetworkEvent<T: GameInput> {
+    Message(NetworkMessage<T>),
+    /// The transport received traffic from the remote for the first time
+    Connected,
+    /// The transport has not heard from the remote in its idle timeout
+    TimedOut,
+}
+
+/// Size of the IP and UDP headers added to every packet, counted when
+/// measuring bandwidth
+const UDP_HEADER_SIZE: u64 = 28;
+
+/// Largest message that will be sent or received, the same as laminar's
+/// default max packet size
+pub const MAX_PACKET_SIZE: usize = 16 * 1024;
 
 /// Handles sending and receiving packets
 pub struct NetworkHandler {
-    /// Listens and sends packets
-    socket: Socket,
+    /// Sends and receives the packets, laminar over UDP unless a different
+    /// transport is given
+    transport: Box<dyn Transport>,
 
-    /// Remote address to send packets
-    remote_addr: SocketAddr,
+    /// Bytes sent including the UDP headers
+    bytes_sent: u64,
+    packets_sent: u64,
+    /// Received packets that were too large or could not be deserialized
+    dropped_packets: u64,
+    /// Time everything above the transport runs on, the transport's own
+    /// clock if it has one
+    clock: Arc<dyn Clock>,
+    created: Duration,
+    last_sent: Duration,
 }
 
 impl NetworkHandler {
     /// Creates a new [NetworkHandler] where client_addr is the address the
     /// client will send from and server_addr is the address the server will
     /// listen on
-    pub fn new(server_addr: SocketAddr, remote_addr: SocketAddr) -> Self {
-        let socket = Socket::bind(server_addr).unwrap();
+    pub fn new(server_addr: SocketAddr, remote_addr: SocketAddr) -> Result<Self, NetworkError> {
+        let transport = LaminarTransport::bind(server_addr, remote_addr)?;
+        Ok(Self::with_transport(transport))
+    }
+
+    /// Creates a new [NetworkHandler] that sends and receives using the given
+    /// transport
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Self {
+        let clock = transport
+            .clock()
+            .unwrap_or_else(|| Arc::new(SystemClock::new()));
+        let now = clock.now();
         NetworkHandler {
-            socket,
-            remote_addr,
+            transport: Box::new(transport),
+            bytes_sent: 0,
+            packets_sent: 0,
+            dropped_packets: 0,
+            clock,
+            created: now,
+            last_sent: now,
+        }
+    }
+
+    /// Current time on the handler's clock
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// When the last packet was queued to be sent
+    pub fn last_sent(&self) -> Duration {
+        self.last_sent
+    }
+
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets
+    }
+
+    /// Average kilobits per second sent since the handler was created
+    pub fn kbps_sent(&self) -> u32 {
+        let seconds = (self.now() - self.created).as_secs_f64();
+        if seconds <= 0.0 {
+            return 0;
         }
+        (self.bytes_sent as f64 * 8.0 / 1000.0 / seconds) as u32
     }
 
-    pub fn get_messages(&mut self) -> Vec<NetworkMessage> {
-        self.socket.manual_poll(Instant::now());
-        let mut messages = Vec::new();
-        while let Some(event) = self.socket.recv() {
+    /// Receive everything from the transport, packets that are too large or
+    /// are not a valid message are dropped
+    pub fn poll_events<T: NetworkInput>(&mut self) -> Vec<NetworkEvent<T>> {
+        let mut events = Vec::new();
+        for event in self.transport.poll() {
             match event {
-                SocketEvent::Packet(packet) => {
-                    let msg = deserialize::<NetworkMessage>(packet.payload()).unwrap();
-                    println!("message: {:#?}", msg);
-                    messages.push(msg);
-                }
-                SocketEvent::Connect(addr) => println!("Connect: {:#?}", addr),
-                SocketEvent::Timeout(addr) => println!("Timeout: {:#?}", addr),
+                TransportEvent::Datagram(payload) => match Self::deserialize(&payload) {
+                    Some(msg) => {
+                        trace!("message: {:#?}", msg);
+                        events.push(NetworkEvent::Message(msg));
+                    }
+                    None => self.dropped_packets += 1,
+                },
+                TransportEvent::Connected => events.push(NetworkEvent::Connected),
+                TransportEvent::TimedOut => events.push(NetworkEvent::TimedOut),
             }
         }
-        messages
+        events
+    }
+
+    fn deserialize<T: NetworkInput>(payload: &[u8]) -> Option<NetworkMessage<T>> {
+        if payload.len() > MAX_PACKET_SIZE {
+            debug!("dropping packet of {} bytes", payload.len());
+            return None;
+        }
+        // same encoding as bincode::deserialize, but the limit stops a bad
+        // length prefix from allocating more than the packet could hold
+        DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_PACKET_SIZE as u64)
+            .deserialize(payload)
+            .map_err(|error| debug!("dropping malformed packet: {}", error))
+            .ok()
     }
 
-    pub fn send_msg_now(&mut self, payload: &NetworkMessage) -> Result<(), ErrorKind> {
+    pub fn get_messages<T: NetworkInput>(&mut self) -> Vec<NetworkMessage<T>> {
+        self.poll_events()
+            .into_iter()
+            .filter_map(|event| match event {
+                NetworkEvent::Message(msg) => Some(msg),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn send_msg_now<T: NetworkInput>(
+        &mut self,
+        payload: &NetworkMessage<T>,
+    ) -> Result<(), NetworkError> {
         self.queue_msg(payload)?;
         self.empty_msg_queue();
         Ok(())
     }
 
-    pub fn queue_msg(&mut self, payload: &NetworkMessage) -> Result<(), ErrorKind> {
-        let packet = Packet::reliable_unordered(self.remote_addr, serialize(payload).unwrap());
-        self.socket.send(packet)
+    pub fn queue_msg<T: NetworkInput>(
+        &mut self,
+        payload: &NetworkMessage<T>,
+    ) -> Result<(), NetworkError> {
+        self.send(serialize(payload)?, Delivery::Reliable)
+    }
+
+    /// Queue a message that may be dropped or arrive out of order, used for
+    /// messages that are resent until the peer acknowledges them
+    pub fn queue_unreliable_msg<T: NetworkInput>(
+        &mut self,
+        payload: &NetworkMessage<T>,
+    ) -> Result<(), NetworkError> {
+        self.send(serialize(payload)?, Delivery::Unreliable)
+    }
+
+    fn send(&mut self, payload: Vec<u8>, delivery: Delivery) -> Result<(), NetworkError> {
+        if payload.len() > MAX_PACKET_SIZE {
+            return Err(NetworkError::PacketTooLarge {
+                size: payload.len(),
+                max: MAX_PACKET_SIZE,
+            });
+        }
+        self.bytes_sent += payload.len() as u64 + UDP_HEADER_SIZE;
+        self.packets_sent += 1;
+        self.last_sent = self.now();
+        self.transport.send(payload, delivery)?;
+        Ok(())
     }
 
     pub fn empty_msg_queue(&mut self) {
-        self.socket.manual_poll(Instant::now())
+        self.transport.flush()
+    }
+
+    /// Stop talking to the remote, nothing is received after this
+    pub fn close(&mut self) {
+        self.transport.close()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    const SERVER_ADDR: &str = "127.0.0.1:12345";
-    const REMOTE_ADDR: &str = "127.0.0.1:12346";
-
-    fn remote_address() -> SocketAddr {
-        REMOTE_ADDR.parse().unwrap()
-    }
-
-    fn server_address() -> SocketAddr {
-        SERVER_ADDR.parse().unwrap()
-    }
+    use crate::network::{
+        codec::{BincodeCodec, EncodedInputs},
+        loopback::{LoopbackNetwork, NetworkConditions},
+    };
 
     #[test]
     fn queue_and_send_messages() {
-        let mut local = NetworkHandler::new(server_address(), remote_address());
-        let mut remote = NetworkHandler::new(remote_address(), server_address());
-        let payload1 = NetworkMessage::make_input("msg1");
-        let payload2 = NetworkMessage::make_input("msg2");
+        let (local, remote) = LoopbackNetwork::new(NetworkConditions::default(), 0)
+            .connect()
+            .unwrap();
+        let mut local = NetworkHandler::with_transport(local);
+        let mut remote = NetworkHandler::with_transport(remote);
+        let payload1 = NetworkMessage::make_input(
+            0,
+            None,
+            vec![],
+            EncodedInputs::encode(&BincodeCodec, &[String::from("msg1")]).unwrap(),
+        );
+        let payload2 = NetworkMessage::make_input(
+            1,
+            None,
+            vec![],
+            EncodedInputs::encode(&BincodeCodec, &[String::from("msg2")]).unwrap(),
+        );
         local.queue_msg(&payload1).unwrap();
         local.queue_msg(&payload2).unwrap();
+        local.empty_msg_queue();
 
-        // queue has not been emptied yet so no messages sent
-        assert_eq!(remote.get_messages(), vec![]);
+        assert_eq!(remote.get_messages(), vec![payload1, payload2]);
+        // nothing is sent back to the local handler
+        assert_eq!(local.get_messages::<String>(), vec![]);
+        assert_eq!(local.packets_sent(), 2);
+    }
 
-        local.empty_msg_queue();
-        assert_eq!(remote.get_messages(), vec![payload1, payload2])
+    #[test]
+    fn drop_malformed_packets() {
+        let (mut local, remote) = LoopbackNetwork::new(NetworkConditions::default(), 0)
+            .connect()
+            .unwrap();
+        let mut remote = NetworkHandler::with_transport(remote);
+        let msg = NetworkMessage::<String>::KeepAlive;
+        local.send(vec![255, 1, 2], Delivery::Unreliable).unwrap();
+        // not one of the message types
+        local
+            .send(serialize(&u32::max_value()).unwrap(), Delivery::Unreliable)
+            .unwrap();
+        local
+            .send(vec![0; MAX_PACKET_SIZE + 1], Delivery::Unreliable)
+            .unwrap();
+        local
+            .send(serialize(&msg).unwrap(), Delivery::Unreliable)
+            .unwrap();
+
+        assert_eq!(remote.get_messages::<String>(), vec![msg]);
+        assert_eq!(remote.dropped_packets(), 3);
+
+        let too_large = NetworkMessage::make_input(
+            0,
+            None,
+            vec![],
+            EncodedInputs::encode(
+                &BincodeCodec,
+                &[String::from_utf8(vec![b'a'; MAX_PACKET_SIZE]).unwrap()],
+            )
+            .unwrap(),
+        );
+        assert!(matches!(
+            remote.queue_msg(&too_large),
+            Err(NetworkError::PacketTooLarge { .. })
+        ));
+        assert_eq!(remote.packets_sent(), 0);
     }
 }
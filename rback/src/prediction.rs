@@ -0,0 +1,89 @@
+use crate::GameInput;
+
+/// Guesses a player's input for frames we have not received yet. A better
+/// guess for a game's inputs means fewer rollbacks when the real input
+/// arrives.
+///
+/// `previous` is the last input we have for the player, None if we have no
+/// inputs for them yet or it was None. The same prediction is used for every
+/// frame until the real inputs arrive.
+pub trait PredictionStrategy<T: GameInput>: Send + Sync {
+    fn predict(&self, previous: Option<&T>) -> Option<T>;
+}
+
+/// Predict the player keeps doing what they were doing, what GGPO does
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RepeatLastInput;
+
+impl<T: GameInput> PredictionStrategy<T> for RepeatLastInput {
+    fn predict(&self, previous: Option<&T>) -> Option<T> {
+        previous.cloned()
+    }
+}
+
+/// Always predict there is no input
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BlankInput;
+
+impl<T: GameInput> PredictionStrategy<T> for BlankInput {
+    fn predict(&self, _previous: Option<&T>) -> Option<T> {
+        None
+    }
+}
+
+/// Always predict the input's default value, like nothing being pressed
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultInput;
+
+impl<T: GameInput + Default> PredictionStrategy<T> for DefaultInput {
+    fn predict(&self, _previous: Option<&T>) -> Option<T> {
+        Some(T::default())
+    }
+}
+
+/// Predict held buttons stay held but edge triggered ones, like a button that
+/// only does something on the frame it is pressed, are released. `held` is
+/// given the previous input and returns it with only the held buttons.
+pub struct RepeatHeldInputs<F> {
+    held: F,
+}
+
+impl<F> RepeatHeldInputs<F> {
+    pub fn new(held: F) -> Self {
+        Self { held }
+    }
+}
+
+impl<T, F> PredictionStrategy<T> for RepeatHeldInputs<F>
+where
+    T: GameInput,
+    F: Fn(&T) -> T + Send + Sync,
+{
+    fn predict(&self, previous: Option<&T>) -> Option<T> {
+        previous.map(|input| (self.held)(input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bit 0 is a held button, bit 1 is edge triggered
+    const HELD: u8 = 0b01;
+    const PRESSED: u8 = 0b10;
+
+    #[test]
+    fn test_strategies() {
+        let previous = Some(&(HELD | PRESSED));
+        assert_eq!(RepeatLastInput.predict(previous), Some(HELD | PRESSED));
+        assert_eq!(
+            PredictionStrategy::<u8>::predict(&BlankInput, previous),
+            None
+        );
+        assert_eq!(DefaultInput.predict(previous), Some(0));
+
+        let strategy = RepeatHeldInputs::new(|input: &u8| input & HELD);
+        assert_eq!(strategy.predict(previous), Some(HELD));
+        assert_eq!(strategy.predict(None), None);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{error::ReplayError, FrameSize, GameInput, NetworkInput, PlayerInput};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+/// Start of every replay file
+const REPLAY_MAGIC: &[u8; 4] = b"RBRP";
+/// Bumped whenever the layout of `Replay` changes so old files are rejected
+/// instead of misread
+pub const REPLAY_VERSION: u16 = 2;
+
+/// Every confirmed input of a match and the settings needed to play it back
+/// with a `ReplaySession`
+///
+/// A file is `REPLAY_MAGIC`, `REPLAY_VERSION` as little endian and then the
+/// replay encoded with bincode.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct Replay<T: GameInput> {
+    pub num_players: u8,
+    pub max_prediction_frames: FrameSize,
+    /// Anything else the game needs to recreate the match, like the stage or
+    /// random seed
+    pub game_settings: Vec<u8>,
+    /// Every player's input for each frame starting at frame 0
+    pub inputs: Vec<Vec<PlayerInput<T>>>,
+}
+
+impl<T: NetworkInput> Replay<T> {
+    pub fn new(num_players: u8, max_prediction_frames: FrameSize, game_settings: Vec<u8>) -> Self {
+        Self {
+            num_players,
+            max_prediction_frames,
+            game_settings,
+            inputs: Vec::new(),
+        }
+    }
+
+    /// Number of frames recorded
+    pub fn len(&self) -> FrameSize {
+        self.inputs.len() as FrameSize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> Result<(), ReplayError> {
+        writer.write_all(REPLAY_MAGIC)?;
+        writer.write_all(&REPLAY_VERSION.to_le_bytes())?;
+        bincode::serialize_into(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self, ReplayError> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            return Err(ReplayError::NotAReplay);
+        }
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != REPLAY_VERSION {
+            return Err(ReplayError::UnsupportedVersion(version));
+        }
+        Ok(bincode::deserialize_from(reader)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ReplayError> {
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ReplayError> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay() -> Replay<u8> {
+        let mut replay = Replay::new(2, 8, vec![42]);
+        for frame in 0..10 {
+            replay.inputs.push(vec![
+                PlayerInput::confirmed(frame),
+                if frame < 5 {
+                    PlayerInput::confirmed(frame * 2)
+                } else {
+                    PlayerInput::disconnected()
+                },
+            ]);
+        }
+        replay
+    }
+
+    #[test]
+    fn test_write_and_read() -> Result<(), ReplayError> {
+        let replay = replay();
+        let mut bytes = Vec::new();
+        replay.write_to(&mut bytes)?;
+        assert_eq!(&bytes[..4], REPLAY_MAGIC);
+        assert_eq!(Replay::read_from(&bytes[..])?, replay);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_bad_header() -> Result<(), ReplayError> {
+        let mut bytes = Vec::new();
+        replay().write_to(&mut bytes)?;
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4..6].copy_from_slice(&(REPLAY_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Replay::<u8>::read_from(&wrong_version[..]),
+            Err(ReplayError::UnsupportedVersion(version)) if version == REPLAY_VERSION + 1
+        ));
+
+        bytes[0] = b'X';
+        assert!(matches!(
+            Replay::<u8>::read_from(&bytes[..]),
+            Err(ReplayError::NotAReplay)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use crate::FrameSize;
+
+#[derive(Debug)]
+struct SavedState<S> {
+    frame: FrameSize,
+    state: S,
+    checksum: Option<u64>,
+}
+
+#[derive(Debug)]
+/// Ring buffer of the game states saved for recent frames
+pub struct SavedStates<S> {
+    states: Vec<Option<SavedState<S>>>,
+}
+
+impl<S> SavedStates<S> {
+    /// The buffer needs to hold every frame we could roll back to, so this
+    /// should be at least `max_prediction_frames + 2`
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            states: (0..capacity).map(|_| None).collect(),
+        }
+    }
+
+    #[inline]
+    fn index(&self, frame: FrameSize) -> usize {
+        frame as usize % self.states.len()
+    }
+
+    /// Save the state for a frame, replacing whatever state was in its slot
+    pub fn save(&mut self, frame: FrameSize, state: S, checksum: Option<u64>) {
+        let idx = self.index(frame);
+        self.states[idx] = Some(SavedState {
+            frame,
+            state,
+            checksum,
+        });
+    }
+
+    fn get_saved(&self, frame: FrameSize) -> Option<&SavedState<S>> {
+        match &self.states[self.index(frame)] {
+            Some(saved) if saved.frame == frame => Some(saved),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, frame: FrameSize) -> Option<&S> {
+        self.get_saved(frame).map(|saved| &saved.state)
+    }
+
+    /// Checksum the game gave with the state, if any
+    pub fn get_checksum(&self, frame: FrameSize) -> Option<u64> {
+        self.get_saved(frame).and_then(|saved| saved.checksum)
+    }
+
+    /// Drop every state saved before the given frame
+    pub fn discard_before(&mut self, frame: FrameSize) {
+        for slot in self.states.iter_mut() {
+            if slot.as_ref().map_or(false, |saved| saved.frame < frame) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.states.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_get() {
+        let mut states = SavedStates::new(4);
+        for frame in 0..4 {
+            states.save(frame, frame * 10, None);
+        }
+        assert_eq!(states.get(0), Some(&0));
+        assert_eq!(states.get(3), Some(&30));
+        assert_eq!(states.len(), 4);
+
+        // wraps around and replaces frame 0
+        states.save(4, 40, Some(4));
+        assert_eq!(states.get(0), None);
+        assert_eq!(states.get(4), Some(&40));
+        assert_eq!(states.get_checksum(4), Some(4));
+
+        // saving a frame again replaces the old state
+        states.save(4, 41, None);
+        assert_eq!(states.get(4), Some(&41));
+        assert_eq!(states.get_checksum(4), None);
+    }
+
+    #[test]
+    fn test_discard_before() {
+        let mut states = SavedStates::new(4);
+        for frame in 0..4 {
+            states.save(frame, frame, None);
+        }
+        states.discard_before(2);
+        assert_eq!(states.get(1), None);
+        assert_eq!(states.get(2), Some(&2));
+        assert_eq!(states.len(), 2);
+    }
+}
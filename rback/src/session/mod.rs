@@ -0,0 +1,194 @@
+use crate::{
+    network::protocol::ProtocolEvent, FrameSize, NetworkInput, PlayerHandle, Request, SyncCallBacks,
+};
+use std::time::Duration;
+
+pub mod p2p;
+pub mod replay;
+pub mod spectator;
+pub mod sync_test;
+/// Helpers for the session tests, which play matches over the loopback network
+#[cfg(test)]
+pub(crate) mod test_util;
+
+/// Notifications about the state of a session
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionEvent {
+    /// Got the first reply from a remote player
+    ConnectedToPeer {
+        player: PlayerHandle,
+    },
+    /// Finished `count` of the `total` sync round trips with a remote player
+    Synchronizing {
+        player: PlayerHandle,
+        total: u32,
+        count: u32,
+    },
+    SynchronizedWithPeer {
+        player: PlayerHandle,
+    },
+    /// Every remote player is synchronized so the match can start
+    Running,
+    /// We are running ahead of the other players, the game should wait this
+    /// many frames (by not advancing) so they can catch up and we roll back
+    /// less
+    TimeSync {
+        frames_ahead: FrameSize,
+    },
+    /// Have not heard from a remote player in a while, they will be
+    /// disconnected if nothing arrives before the timeout
+    NetworkInterrupted {
+        player: PlayerHandle,
+        disconnect_timeout: Duration,
+    },
+    /// Heard from an interrupted remote player again
+    NetworkResumed {
+        player: PlayerHandle,
+    },
+    /// The game state for a confirmed frame is different for a remote player
+    /// so the game is out of sync. Only sent if the game gives checksums when
+    /// saving states
+    DesyncDetected {
+        player: PlayerHandle,
+        frame: FrameSize,
+        local: u64,
+        remote: u64,
+    },
+    /// Every player agreed on a new frame delay for the local input starting
+    /// on `frame`, only sent when adaptive frame delay is on
+    FrameDelayChanged {
+        frame: FrameSize,
+        delay: FrameSize,
+    },
+    /// The remote player timed out or was dropped by us or another peer,
+    /// their inputs will be given as disconnected from now on
+    DisconnectedFromPeer {
+        player: PlayerHandle,
+    },
+}
+
+/// Convert a peer's protocol event to the session event the game sees,
+/// inputs, checksums and frame delays are handled by the session so they give
+/// None
+pub(crate) fn peer_event<I: NetworkInput>(
+    player: PlayerHandle,
+    event: ProtocolEvent<I>,
+) -> Option<SessionEvent> {
+    match event {
+        ProtocolEvent::Input(_)
+        | ProtocolEvent::Checksum { .. }
+        | ProtocolEvent::FrameDelay { .. } => None,
+        ProtocolEvent::Connected => Some(SessionEvent::ConnectedToPeer { player }),
+        ProtocolEvent::Synchronizing { total, count } => Some(SessionEvent::Synchronizing {
+            player,
+            total,
+            count,
+        }),
+        ProtocolEvent::Synchronized => Some(SessionEvent::SynchronizedWithPeer { player }),
+        ProtocolEvent::NetworkInterrupted { disconnect_timeout } => {
+            Some(SessionEvent::NetworkInterrupted {
+                player,
+                disconnect_timeout,
+            })
+        }
+        ProtocolEvent::NetworkResumed => Some(SessionEvent::NetworkResumed { player }),
+        ProtocolEvent::Disconnected => Some(SessionEvent::DisconnectedFromPeer { player }),
+    }
+}
+
+/// Perform requests on behalf of the game using its callbacks, each saved
+/// state and its checksum is passed to `save_state` so the session can store
+/// it
+pub(crate) fn handle_requests<C, F>(
+    requests: Vec<Request<C::Input, C::SavedState>>,
+    callbacks: &mut C,
+    mut save_state: F,
+) where
+    C: SyncCallBacks,
+    F: FnMut(FrameSize, C::SavedState, Option<u64>),
+{
+    for request in requests {
+        match request {
+            Request::SaveGameState { frame } => {
+                let state = callbacks.save_game_state(frame);
+                let checksum = callbacks.checksum(&state, frame);
+                save_state(frame, state, checksum);
+            }
+            Request::LoadGameState { frame, state } => callbacks.load_game_state(state, frame),
+            Request::AdvanceFrame { inputs } => callbacks.advance_frame(inputs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sync::Sync, PlayerInput};
+
+    /// Game where the state is the sum of every input
+    #[derive(Default)]
+    struct SumGame {
+        sum: u32,
+        events: Vec<SessionEvent>,
+    }
+
+    impl SyncCallBacks for SumGame {
+        type Input = u32;
+        type SavedState = u32;
+
+        fn save_game_state(&mut self, _frame: FrameSize) -> u32 {
+            self.sum
+        }
+
+        fn checksum(&mut self, saved_state: &u32, _frame: FrameSize) -> Option<u64> {
+            Some(*saved_state as u64)
+        }
+
+        fn load_game_state(&mut self, saved_state: u32, _frame: FrameSize) {
+            self.sum = saved_state;
+        }
+
+        fn advance_frame(&mut self, inputs: Vec<PlayerInput<u32>>) {
+            self.sum += inputs
+                .into_iter()
+                .map(|i| i.input.unwrap_or(0))
+                .sum::<u32>();
+        }
+
+        fn on_event(&mut self, event: SessionEvent) {
+            self.events.push(event);
+        }
+    }
+
+    #[test]
+    fn test_handle_requests() -> Result<(), crate::error::SyncError> {
+        let mut sync: Sync<u32, u32> = Sync::new(4, 2);
+        let mut game = SumGame::default();
+
+        sync.add_local_input(0, (1, 0).into())?;
+        handle_requests(
+            sync.advance_frame()?,
+            &mut game,
+            |frame, state, checksum| sync.save_game_state(frame, state, checksum),
+        );
+        sync.add_local_input(0, (2, 1).into())?;
+        handle_requests(
+            sync.advance_frame()?,
+            &mut game,
+            |frame, state, checksum| sync.save_game_state(frame, state, checksum),
+        );
+        assert_eq!(game.sum, 3);
+
+        // late remote input rolls back and resimulates both frames
+        sync.add_local_input(0, (3, 2).into())?;
+        sync.add_remote_input(1, (10, 0).into())?;
+        handle_requests(
+            sync.advance_frame()?,
+            &mut game,
+            |frame, state, checksum| sync.save_game_state(frame, state, checksum),
+        );
+        assert_eq!(game.sum, 1 + 2 + 3 + 10 * 3);
+        assert_eq!(sync.checksum(2), Some(1 + 2 + 10 * 2));
+        Ok(())
+    }
+}
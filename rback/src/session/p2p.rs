@@ -0,0 +1,1184 @@
+use crate::{
+    error::SessionError,
+    frame_delay::{AdaptiveFrameDelay, FrameDelayConfig},
+    game_input_frame::ConfirmedInput,
+    network::{
+        codec::{BincodeCodec, InputCodec},
+        protocol::{NetworkStats, PeerProtocol, ProtocolConfig, ProtocolEvent},
+        udp::NetworkHandler,
+    },
+    prediction::PredictionStrategy,
+    replay::Replay,
+    session::{handle_requests, peer_event, SessionEvent},
+    sync::Sync,
+    FrameIndex, FrameSize, NetworkInput, PlayerHandle, PlayerInput, Request, SyncCallBacks,
+};
+use std::{cmp::min, collections::VecDeque, sync::Arc};
+
+/// Number of checksums kept while waiting for the matching local or remote
+/// checksum
+const MAX_CHECKSUM_HISTORY: usize = 32;
+
+/// How often to check if we should wait for the other players
+// https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/backends/p2p.cpp#L11
+const RECOMMENDATION_INTERVAL: FrameSize = 240;
+
+/// A remote player and the connection used to talk to them
+struct RemotePeer<T: NetworkInput> {
+    handle: PlayerHandle,
+    protocol: PeerProtocol<T>,
+    /// Checksums from the peer we do not have a local checksum for yet
+    checksums: Vec<(FrameSize, u64)>,
+    /// Last frame delay round of the peer's we answered without proposing
+    answered_round: FrameIndex,
+}
+
+/// Session between a local player and one or more remote players, similar to
+/// GGPO's Peer2PeerBackend
+///
+/// Each frame the game should call `add_local_input` followed by
+/// `advance_frame` and then perform the returned requests in order, handing
+/// saved states back with `save_game_state`. `poll`
+/// can be called while waiting for the next frame to keep the network moving.
+pub struct P2PSession<T: NetworkInput, S> {
+    sync: Sync<T, S>,
+    local_player: PlayerHandle,
+    peers: Vec<RemotePeer<T>>,
+    protocol_config: ProtocolConfig,
+    codec: Arc<dyn InputCodec<T>>,
+    /// Connections to spectators, they are sent every player's input once it
+    /// is confirmed
+    spectators: Vec<PeerProtocol<Vec<PlayerInput<T>>>>,
+    /// Confirmed inputs of every frame when recording
+    recording: Option<Replay<T>>,
+    /// Next confirmed frame to send to spectators and the recording
+    next_streamed_frame: FrameSize,
+    /// Frame to next check if we are running ahead
+    next_recommended_sleep: FrameSize,
+    /// Send the checksum of every confirmed frame that is a multiple of this
+    desync_detection: Option<FrameSize>,
+    next_checksum_frame: FrameSize,
+    /// Our most recent checksums sent to peers
+    local_checksums: VecDeque<(FrameSize, u64)>,
+    /// Frames the local player's inputs are delayed by
+    frame_delay: FrameSize,
+    /// Adjusts the frame delay during the match when set
+    adaptive_frame_delay: Option<AdaptiveFrameDelay>,
+    /// Next local input frame to send to peers
+    next_local_frame: FrameSize,
+    /// Set once every peer has synchronized
+    running: bool,
+    events: VecDeque<SessionEvent>,
+}
+
+impl<T: NetworkInput, S: Clone> P2PSession<T, S> {
+    pub fn new(
+        max_prediction_frames: FrameSize,
+        num_players: u8,
+        local_player: PlayerHandle,
+    ) -> Self {
+        Self {
+            sync: Sync::new(max_prediction_frames, num_players),
+            local_player,
+            peers: Vec::new(),
+            protocol_config: ProtocolConfig::default(),
+            codec: Arc::new(BincodeCodec),
+            spectators: Vec::new(),
+            recording: None,
+            next_streamed_frame: 0,
+            next_recommended_sleep: 0,
+            desync_detection: None,
+            next_checksum_frame: 0,
+            local_checksums: VecDeque::new(),
+            frame_delay: 0,
+            adaptive_frame_delay: None,
+            next_local_frame: 0,
+            running: false,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Register a remote player whose inputs will be sent and received over
+    /// the given handler
+    pub fn add_remote_player(
+        &mut self,
+        handle: PlayerHandle,
+        handler: NetworkHandler,
+    ) -> Result<(), SessionError> {
+        if handle >= self.sync.num_players() || handle == self.local_player {
+            return Err(SessionError::UnknownPlayer(handle));
+        }
+        let mut protocol = PeerProtocol::new(handler, self.protocol_config.clone());
+        protocol.set_codec(self.codec.clone());
+        self.peers.push(RemotePeer {
+            handle,
+            protocol,
+            checksums: Vec::new(),
+            answered_round: None,
+        });
+        Ok(())
+    }
+
+    /// Stream confirmed inputs to a `SpectatorSession` over the given
+    /// handler. Spectators should be added before the match starts.
+    pub fn add_spectator(&mut self, handler: NetworkHandler) {
+        self.spectators
+            .push(PeerProtocol::new(handler, self.protocol_config.clone()));
+    }
+
+    /// Record every confirmed input so the match can be played back with a
+    /// `ReplaySession`. `game_settings` is stored in the replay as is.
+    /// Recording should be started before the match starts.
+    pub fn start_recording(&mut self, game_settings: Vec<u8>) {
+        self.recording = Some(Replay::new(
+            self.sync.num_players(),
+            self.sync.max_prediction_frames(),
+            game_settings,
+        ));
+    }
+
+    /// Stop recording and take the replay of every frame confirmed so far
+    pub fn take_replay(&mut self) -> Option<Replay<T>> {
+        self.recording.take()
+    }
+
+    pub fn set_protocol_config(&mut self, config: ProtocolConfig) {
+        for peer in self.peers.iter_mut() {
+            peer.protocol.set_config(config.clone());
+        }
+        for spectator in self.spectators.iter_mut() {
+            spectator.set_config(config.clone());
+        }
+        self.protocol_config = config;
+    }
+
+    /// Exchange checksums with the other players every `interval` confirmed
+    /// frames and send `SessionEvent::DesyncDetected` if they differ. The game
+    /// has to give checksums to `save_game_state` for this to do anything.
+    /// None turns it off, which is the default.
+    pub fn set_desync_detection(&mut self, interval: Option<FrameSize>) {
+        self.desync_detection = interval.filter(|interval| *interval > 0);
+    }
+
+    /// Use a custom codec to pack inputs sent over the network instead of
+    /// bincode. Every player has to use the same codec.
+    pub fn set_input_codec<C: InputCodec<T> + 'static>(&mut self, codec: C) {
+        self.codec = Arc::new(codec);
+        for peer in self.peers.iter_mut() {
+            peer.protocol.set_codec(self.codec.clone());
+        }
+    }
+
+    /// Use a different strategy to guess the remote players' inputs before
+    /// they arrive, by default the last input is repeated
+    pub fn set_prediction_strategy<P: PredictionStrategy<T> + 'static>(&mut self, strategy: P) {
+        self.sync.set_prediction_strategy(Arc::new(strategy));
+    }
+
+    /// Delay the local player's inputs by this many frames, this hides
+    /// latency so there are fewer rollbacks but the game feels less
+    /// responsive
+    pub fn set_frame_delay(&mut self, delay: FrameSize) -> Result<(), SessionError> {
+        self.sync.set_frame_delay(self.local_player, delay)?;
+        self.frame_delay = delay;
+        if let Some(adaptive) = self.adaptive_frame_delay.as_mut() {
+            adaptive.set_delay(delay);
+        }
+        Ok(())
+    }
+
+    pub fn frame_delay(&self) -> FrameSize {
+        self.frame_delay
+    }
+
+    /// Adjust the frame delay during the match from the round trip time and
+    /// how often we roll back. The players agree on each change so it
+    /// happens on the same frame for everyone and every player using it has
+    /// to use the same interval. When a change is due before every player's
+    /// proposal arrived `add_local_input` returns
+    /// `SessionError::FrameDelayPending` until it does. Players that don't
+    /// use it answer proposals with their own delay so nobody waits on them.
+    /// It can be turned on during the match, the first proposal is made on
+    /// the next round. None turns it off, which is the default.
+    pub fn set_adaptive_frame_delay(&mut self, config: Option<FrameDelayConfig>) {
+        let delay = self.frame_delay;
+        let frame = self.sync.frame_count;
+        self.adaptive_frame_delay = config
+            .filter(|config| config.interval > 0)
+            .map(|config| AdaptiveFrameDelay::new(config, delay, frame));
+    }
+
+    /// True once every remote player has synchronized and inputs can be
+    /// added
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn local_player(&self) -> PlayerHandle {
+        self.local_player
+    }
+
+    /// Frame that will be simulated by the next `advance_frame`
+    pub fn current_frame(&self) -> FrameSize {
+        self.sync.frame_count
+    }
+
+    /// Connection quality to a remote player
+    pub fn network_stats(&self, handle: PlayerHandle) -> Result<NetworkStats, SessionError> {
+        self.peers
+            .iter()
+            .find(|peer| peer.handle == handle)
+            .map(|peer| peer.protocol.network_stats())
+            .ok_or(SessionError::UnknownPlayer(handle))
+    }
+
+    /// Add the local player's input for the current frame and queue it to be
+    /// sent to every remote player
+    pub fn add_local_input(&mut self, input: T) -> Result<(), SessionError> {
+        if !self.running {
+            return Err(SessionError::NotSynchronized);
+        }
+        if !self.apply_agreed_frame_delay()? {
+            return Err(SessionError::FrameDelayPending);
+        }
+        let frame = self.sync.frame_count;
+        let added = self
+            .sync
+            .add_local_input(self.local_player, ConfirmedInput::new(input, frame))?;
+
+        // raising the frame delay fills the frames it skips with the last
+        // input, the peers need those too
+        let mut inputs = Vec::new();
+        if let Some(added) = added {
+            for frame in self.next_local_frame..added.frame {
+                let skipped = self.sync.get_confirmed_input(self.local_player, frame)?;
+                inputs.push(skipped.clone());
+            }
+            self.next_local_frame = added.frame + 1;
+            inputs.push(added);
+        }
+        // the input is already added so every peer has to get it even if
+        // sending to one of them fails
+        let mut result = Ok(());
+        for peer in self.peers.iter_mut() {
+            peer.protocol
+                .set_local_connect_status(self.sync.connection_status());
+            for input in inputs.iter() {
+                keep_first_error(&mut result, peer.protocol.send_input(input.clone()));
+            }
+        }
+        result
+    }
+
+    /// Remove a remote player from the match, their inputs will be given as
+    /// disconnected after the last frame we received from them. The other
+    /// peers are told so everyone drops them on the same frame.
+    pub fn disconnect_player(&mut self, handle: PlayerHandle) -> Result<(), SessionError> {
+        if !self.peers.iter().any(|peer| peer.handle == handle) {
+            return Err(SessionError::UnknownPlayer(handle));
+        }
+        let last_frame = self.sync.connection_status()[handle as usize].last_frame;
+        self.drop_player(handle, last_frame)
+    }
+
+    /// Called by the game with its state when handling a
+    /// `Request::SaveGameState`. The checksum is optional and is used to
+    /// detect desyncs
+    pub fn save_game_state(&mut self, frame: FrameSize, state: S, checksum: Option<u64>) {
+        self.sync.save_game_state(frame, state, checksum);
+    }
+
+    /// Send any queued messages, synchronize with peers and add received
+    /// remote inputs to their queues
+    ///
+    /// Every peer is handled even if one of them fails, the first error is
+    /// returned after.
+    pub fn poll(&mut self) -> Result<(), SessionError> {
+        let mut result = Ok(());
+        let mut timed_out = Vec::new();
+        for peer in self.peers.iter_mut() {
+            let player = peer.handle;
+            let checksums = &mut peer.checksums;
+            peer.protocol.set_local_frame(self.sync.frame_count);
+            peer.protocol
+                .set_local_connect_status(self.sync.connection_status());
+            let events = match peer.protocol.poll() {
+                Ok(events) => events,
+                Err(error) => {
+                    keep_first_error(&mut result, Err(error));
+                    continue;
+                }
+            };
+            for event in events {
+                match event {
+                    ProtocolEvent::Input(input) => {
+                        let added = self.sync.add_remote_input(player, input);
+                        keep_first_error(&mut result, added.map(|_| ()));
+                    }
+                    ProtocolEvent::Disconnected => timed_out.push(player),
+                    ProtocolEvent::Checksum { frame, checksum } => {
+                        checksums.push((frame, checksum));
+                    }
+                    ProtocolEvent::FrameDelay { frame, delay } => {
+                        match self.adaptive_frame_delay.as_mut() {
+                            Some(adaptive) if !adaptive.needs_answer(frame) => {
+                                adaptive.add_proposal(frame, player, delay)
+                            }
+                            // we won't propose for this round, answer once
+                            // with our delay so the peer isn't stuck waiting
+                            _ if peer.answered_round.map_or(true, |round| frame > round) => {
+                                peer.answered_round = Some(frame);
+                                let answer =
+                                    peer.protocol.send_frame_delay(frame, self.frame_delay);
+                                keep_first_error(&mut result, answer);
+                            }
+                            _ => {}
+                        }
+                    }
+                    event => {
+                        if let Some(event) = peer_event(player, event) {
+                            self.events.push_back(event);
+                        }
+                    }
+                }
+            }
+        }
+        for player in timed_out {
+            keep_first_error(&mut result, self.disconnect_player(player));
+        }
+        keep_first_error(&mut result, self.check_peer_disconnects());
+        // like GGPO confirm frames while polling so a peer waiting on us at
+        // the prediction barrier is not stuck
+        if self.running {
+            keep_first_error(&mut result, self.update_confirmed_frame());
+            // proposals for the frame delay may have just arrived
+            keep_first_error(&mut result, self.apply_agreed_frame_delay().map(|_| ()));
+        }
+        self.compare_checksums();
+        // spectators only send acks so there is nothing to handle, one that
+        // falls too far behind is disconnected by its protocol
+        for spectator in self.spectators.iter_mut() {
+            keep_first_error(&mut result, spectator.poll().map(|_| ()));
+        }
+
+        if !self.running
+            && self.peers.iter().all(|peer| peer.protocol.is_running())
+            && self
+                .spectators
+                .iter()
+                .all(|spectator| spectator.is_running())
+        {
+            self.running = true;
+            self.events.push_back(SessionEvent::Running);
+        }
+        result
+    }
+
+    /// Take every event that happened since the last call
+    pub fn events(&mut self) -> Vec<SessionEvent> {
+        self.events.drain(..).collect()
+    }
+
+    /// Advance the session by one frame. The returned requests should be
+    /// performed in order, they will roll back if a misprediction was
+    /// detected and then simulate the current frame.
+    pub fn advance_frame(&mut self) -> Result<Vec<Request<T, S>>, SessionError> {
+        self.poll()?;
+        if !self.running {
+            return Err(SessionError::NotSynchronized);
+        }
+        let requests = self.sync.advance_frame()?;
+        // only safe to discard inputs once any rollback has been done
+        self.update_confirmed_frame()?;
+        self.check_time_sync();
+        let rolled_back = requests
+            .iter()
+            .any(|request| matches!(request, Request::LoadGameState { .. }));
+        self.adjust_frame_delay(rolled_back)?;
+        Ok(requests)
+    }
+
+    /// Same as `poll` but any events are passed to `callbacks.on_event`
+    pub fn poll_with_callbacks<C>(&mut self, callbacks: &mut C) -> Result<(), SessionError>
+    where
+        C: SyncCallBacks<Input = T, SavedState = S>,
+    {
+        self.poll()?;
+        self.dispatch_events(callbacks);
+        Ok(())
+    }
+
+    /// Same as `advance_frame` but the requests are performed using the
+    /// game's callbacks instead of being returned
+    pub fn advance_frame_with_callbacks<C>(&mut self, callbacks: &mut C) -> Result<(), SessionError>
+    where
+        C: SyncCallBacks<Input = T, SavedState = S>,
+    {
+        let requests = self.advance_frame()?;
+        let sync = &mut self.sync;
+        handle_requests(requests, callbacks, |frame, state, checksum| {
+            sync.save_game_state(frame, state, checksum)
+        });
+        self.dispatch_events(callbacks);
+        Ok(())
+    }
+
+    fn dispatch_events<C>(&mut self, callbacks: &mut C)
+    where
+        C: SyncCallBacks<Input = T, SavedState = S>,
+    {
+        for event in self.events.drain(..) {
+            callbacks.on_event(event);
+        }
+    }
+
+    /// Periodically tell the game to wait if we are ahead of the slowest peer
+    fn check_time_sync(&mut self) {
+        let frame = self.sync.frame_count;
+        if frame < self.next_recommended_sleep {
+            return;
+        }
+        self.next_recommended_sleep = frame + RECOMMENDATION_INTERVAL;
+        let frames_ahead = self
+            .peers
+            .iter()
+            .map(|peer| peer.protocol.recommend_frame_delay())
+            .max()
+            .unwrap_or(0);
+        if frames_ahead > 0 {
+            self.events
+                .push_back(SessionEvent::TimeSync { frames_ahead });
+        }
+    }
+
+    /// Propose a frame delay to the other players every interval and switch
+    /// to the largest proposal once every connected player has sent theirs
+    fn adjust_frame_delay(&mut self, rolled_back: bool) -> Result<(), SessionError> {
+        let adaptive = match self.adaptive_frame_delay.as_mut() {
+            Some(adaptive) => adaptive,
+            None => return Ok(()),
+        };
+        adaptive.record_frame(rolled_back);
+        let frame = self.sync.frame_count;
+        let sync = &self.sync;
+        if adaptive.is_round(frame) {
+            let round_trip_time = self
+                .peers
+                .iter()
+                .filter(|peer| !sync.is_disconnected(peer.handle))
+                .map(|peer| peer.protocol.round_trip_time())
+                .max()
+                .unwrap_or(0);
+            let delay = adaptive.recommend(round_trip_time);
+            adaptive.add_proposal(frame, self.local_player, delay);
+            let mut result = Ok(());
+            for peer in self.peers.iter_mut() {
+                keep_first_error(&mut result, peer.protocol.send_frame_delay(frame, delay));
+            }
+            result?;
+        }
+        self.apply_agreed_frame_delay()?;
+        Ok(())
+    }
+
+    /// Switch to the agreed frame delay if a round is due on the current
+    /// frame. Returns false if we still need proposals from other players
+    /// before the frame can be simulated.
+    fn apply_agreed_frame_delay(&mut self) -> Result<bool, SessionError> {
+        let adaptive = match self.adaptive_frame_delay.as_mut() {
+            Some(adaptive) => adaptive,
+            None => return Ok(true),
+        };
+        let frame = self.sync.frame_count;
+        let sync = &self.sync;
+        let players: Vec<PlayerHandle> = (0..sync.num_players())
+            .filter(|player| !sync.is_disconnected(*player))
+            .collect();
+        if let Some(delay) = adaptive.agreed_delay(frame, &players) {
+            if delay != self.frame_delay {
+                self.sync.set_frame_delay(self.local_player, delay)?;
+                self.frame_delay = delay;
+                self.events
+                    .push_back(SessionEvent::FrameDelayChanged { frame, delay });
+            }
+        }
+        Ok(!adaptive.is_waiting(frame, self.local_player))
+    }
+
+    /// Drop any player another peer says has disconnected, using the
+    /// earliest frame anyone has for them
+    // https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/backends/p2p.cpp#L290
+    fn check_peer_disconnects(&mut self) -> Result<(), SessionError> {
+        for player in 0..self.sync.num_players() {
+            if player == self.local_player {
+                continue;
+            }
+            let mut disconnected = false;
+            let mut last_frame = self.sync.connection_status()[player as usize].last_frame;
+            for peer in self.connected_peers().filter(|peer| peer.handle != player) {
+                if let Some(status) = peer.protocol.peer_connect_status(player) {
+                    disconnected |= status.disconnected;
+                    last_frame = min(last_frame, status.last_frame);
+                }
+            }
+            if disconnected {
+                self.drop_player(player, last_frame)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn drop_player(
+        &mut self,
+        player: PlayerHandle,
+        last_frame: FrameIndex,
+    ) -> Result<(), SessionError> {
+        let already_disconnected = self.sync.is_disconnected(player);
+        self.sync.disconnect_player(player, last_frame)?;
+        if !already_disconnected {
+            if let Some(peer) = self.peers.iter_mut().find(|peer| peer.handle == player) {
+                peer.protocol.disconnect();
+            }
+            self.events
+                .push_back(SessionEvent::DisconnectedFromPeer { player });
+        }
+        Ok(())
+    }
+
+    fn connected_peers(&self) -> impl Iterator<Item = &RemotePeer<T>> {
+        let sync = &self.sync;
+        self.peers
+            .iter()
+            .filter(move |peer| !sync.is_disconnected(peer.handle))
+    }
+
+    fn update_confirmed_frame(&mut self) -> Result<(), SessionError> {
+        // a frame is only confirmed once every connected peer has the
+        // inputs for it, disconnected players will never confirm another
+        let mut confirmed_frame: Option<FrameIndex> = None;
+        for player in self.connected_peers().map(|peer| peer.handle) {
+            let mut last_frame = self.sync.connection_status()[player as usize].last_frame;
+            for peer in self.connected_peers().filter(|peer| peer.handle != player) {
+                let reported = peer
+                    .protocol
+                    .peer_connect_status(player)
+                    .and_then(|status| status.last_frame);
+                last_frame = min(last_frame, reported);
+            }
+            confirmed_frame =
+                Some(confirmed_frame.map_or(last_frame, |frame| min(frame, last_frame)));
+        }
+        // with nobody left to wait for every frame we simulated is confirmed
+        let confirmed_frame =
+            confirmed_frame.unwrap_or_else(|| self.sync.frame_count.checked_sub(1));
+        if let Some(frame) = confirmed_frame {
+            // like GGPO our own inputs count too, we only have them up to the
+            // frame we just simulated
+            let mut frame = min(frame, self.sync.frame_count.saturating_sub(1));
+            // the states and inputs from a pending rollback are still needed
+            if let Some(seek_to) = self.sync.check_simulation_consistency() {
+                frame = min(frame, seek_to);
+            }
+            // has to happen before the inputs and states are discarded
+            self.stream_confirmed_inputs(frame)?;
+            self.send_checksums(frame)?;
+            self.sync.set_last_confirmed_frame(frame);
+        }
+        Ok(())
+    }
+
+    fn send_checksums(&mut self, confirmed_frame: FrameSize) -> Result<(), SessionError> {
+        let interval = match self.desync_detection {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        // the game has not handed back the state for the frame we just
+        // simulated yet
+        if self.sync.frame_count < 2 {
+            return Ok(());
+        }
+        let last_frame = min(confirmed_frame, self.sync.frame_count - 2);
+        while self.next_checksum_frame <= last_frame {
+            let frame = self.next_checksum_frame;
+            self.next_checksum_frame += interval;
+            if let Some(checksum) = self.sync.checksum(frame) {
+                let mut result = Ok(());
+                for peer in self.peers.iter_mut() {
+                    keep_first_error(&mut result, peer.protocol.send_checksum(frame, checksum));
+                }
+                result?;
+                self.local_checksums.push_back((frame, checksum));
+                if self.local_checksums.len() > MAX_CHECKSUM_HISTORY {
+                    self.local_checksums.pop_front();
+                }
+            }
+        }
+        self.compare_checksums();
+        Ok(())
+    }
+
+    fn compare_checksums(&mut self) {
+        let oldest_frame = self.local_checksums.front().map(|(frame, _)| *frame);
+        for peer in self.peers.iter_mut() {
+            let player = peer.handle;
+            let local_checksums = &self.local_checksums;
+            let events = &mut self.events;
+            peer.checksums.retain(|(frame, remote)| {
+                let local = local_checksums
+                    .iter()
+                    .find(|(local_frame, _)| local_frame == frame);
+                match local {
+                    Some((_, local)) => {
+                        if local != remote {
+                            events.push_back(SessionEvent::DesyncDetected {
+                                player,
+                                frame: *frame,
+                                local: *local,
+                                remote: *remote,
+                            });
+                        }
+                        false
+                    }
+                    // keep it until we confirm the frame unless it is too old
+                    None => oldest_frame.map_or(true, |oldest| *frame > oldest),
+                }
+            });
+            let excess = peer.checksums.len().saturating_sub(MAX_CHECKSUM_HISTORY);
+            peer.checksums.drain(..excess);
+        }
+    }
+
+    /// Send newly confirmed inputs to the spectators and add them to the
+    /// recording
+    fn stream_confirmed_inputs(&mut self, confirmed_frame: FrameSize) -> Result<(), SessionError> {
+        if self.spectators.is_empty() && self.recording.is_none() {
+            self.next_streamed_frame = confirmed_frame + 1;
+            return Ok(());
+        }
+        while self.next_streamed_frame <= confirmed_frame {
+            let frame = self.next_streamed_frame;
+            let inputs = self.sync.get_confirmed_inputs(frame)?;
+            // the frame is queued for every spectator even if sending fails
+            // so it is never sent twice
+            let mut result = Ok(());
+            for spectator in self.spectators.iter_mut() {
+                let input = ConfirmedInput::new(inputs.clone(), frame);
+                keep_first_error(&mut result, spectator.send_input(input));
+            }
+            if let Some(recording) = self.recording.as_mut() {
+                recording.inputs.push(inputs);
+            }
+            self.next_streamed_frame += 1;
+            result?;
+        }
+        Ok(())
+    }
+}
+
+/// Remember the first error so the rest of the peers are still handled
+fn keep_first_error<E: Into<SessionError>>(
+    result: &mut Result<(), SessionError>,
+    next: Result<(), E>,
+) {
+    if let Err(error) = next {
+        if result.is_ok() {
+            *result = Err(error.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::SyncError,
+        network::loopback::{LoopbackNetwork, LoopbackTransport, NetworkConditions},
+        session::test_util::{advance_frame, poll_until, session, synchronize},
+    };
+    use std::{collections::HashMap, time::Duration};
+
+    fn advance(inputs: Vec<PlayerInput<u8>>) -> Request<u8, FrameSize> {
+        Request::AdvanceFrame { inputs }
+    }
+
+    #[test]
+    fn test_exchange_inputs() -> Result<(), SessionError> {
+        let config = ProtocolConfig {
+            num_sync_packets: 3,
+            ..ProtocolConfig::default()
+        };
+        let (local_transport, remote_transport) =
+            LoopbackNetwork::new(NetworkConditions::default(), 0).connect()?;
+        let mut local: P2PSession<u8, FrameSize> = P2PSession::new(8, 2, 0);
+        local.set_protocol_config(config.clone());
+        local.add_remote_player(1, NetworkHandler::with_transport(local_transport))?;
+        let mut remote: P2PSession<u8, FrameSize> = P2PSession::new(8, 2, 1);
+        remote.set_protocol_config(config);
+        remote.add_remote_player(0, NetworkHandler::with_transport(remote_transport))?;
+
+        // can't play until synchronized
+        assert!(matches!(
+            local.add_local_input(1),
+            Err(SessionError::NotSynchronized)
+        ));
+
+        let (local_events, remote_events) = synchronize(&mut local, &mut remote)?;
+        assert_eq!(
+            local_events,
+            vec![
+                SessionEvent::ConnectedToPeer { player: 1 },
+                SessionEvent::Synchronizing {
+                    player: 1,
+                    total: 3,
+                    count: 1
+                },
+                SessionEvent::Synchronizing {
+                    player: 1,
+                    total: 3,
+                    count: 2
+                },
+                SessionEvent::SynchronizedWithPeer { player: 1 },
+                SessionEvent::Running,
+            ]
+        );
+        assert_eq!(remote_events.last(), Some(&SessionEvent::Running));
+        assert!(local.network_stats(1).is_ok());
+        assert!(matches!(
+            local.network_stats(0),
+            Err(SessionError::UnknownPlayer(0))
+        ));
+
+        // nothing from the remote yet so predict a blank input for them
+        local.add_local_input(1)?;
+        assert_eq!(
+            advance_frame(&mut local)?,
+            vec![
+                Request::SaveGameState { frame: 0 },
+                advance(vec![
+                    PlayerInput::confirmed(1),
+                    PlayerInput::predicted(None)
+                ]),
+            ]
+        );
+
+        // remote got the local input before advancing so no prediction needed
+        remote.add_local_input(2)?;
+        assert_eq!(
+            advance_frame(&mut remote)?,
+            vec![
+                Request::SaveGameState { frame: 0 },
+                advance(vec![PlayerInput::confirmed(1), PlayerInput::confirmed(2)]),
+            ]
+        );
+
+        // local predicted wrong so it has to roll back to frame 0
+        local.add_local_input(3)?;
+        assert_eq!(
+            advance_frame(&mut local)?,
+            vec![
+                Request::LoadGameState { frame: 0, state: 0 },
+                advance(vec![PlayerInput::confirmed(1), PlayerInput::confirmed(2)]),
+                Request::SaveGameState { frame: 1 },
+                advance(vec![
+                    PlayerInput::confirmed(3),
+                    PlayerInput::predicted(Some(2))
+                ]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_disconnect_player() -> Result<(), SessionError> {
+        let network = LoopbackNetwork::new(NetworkConditions::default(), 0);
+        let (a1, b0) = network.connect()?;
+        let (a2, c0) = network.connect()?;
+        let (b2, c1) = network.connect()?;
+        let mut sessions = vec![
+            session(3, 0, vec![(1, a1), (2, a2)])?,
+            session(3, 1, vec![(0, b0), (2, b2)])?,
+            session(3, 2, vec![(0, c0), (1, c1)])?,
+        ];
+        poll_until(|| {
+            for session in sessions.iter_mut() {
+                session.poll()?;
+            }
+            Ok(sessions.iter().all(|session| session.is_running()))
+        })?;
+
+        for frame in 0..2 {
+            for session in sessions.iter_mut() {
+                session.add_local_input(frame)?;
+                advance_frame(session)?;
+            }
+        }
+        // everyone has every input for frames 0 and 1
+        poll_until(|| {
+            for session in sessions.iter_mut() {
+                session.poll()?;
+            }
+            Ok(sessions.iter().all(|session| {
+                session
+                    .sync
+                    .connection_status()
+                    .iter()
+                    .all(|status| status.last_frame == Some(1))
+            }))
+        })?;
+
+        // the first player drops the third, which the second hears about
+        // through the first's next input
+        sessions[0].disconnect_player(2)?;
+        assert!(matches!(
+            sessions[0].disconnect_player(0),
+            Err(SessionError::UnknownPlayer(0))
+        ));
+        sessions[0].add_local_input(2)?;
+        advance_frame(&mut sessions[0])?;
+        let second = &mut sessions[1];
+        poll_until(|| {
+            second.poll()?;
+            Ok(second.sync.is_disconnected(2))
+        })?;
+        assert!(second
+            .events()
+            .contains(&SessionEvent::DisconnectedFromPeer { player: 2 }));
+        assert_eq!(
+            second.sync.connection_status()[2].last_frame,
+            Some(1),
+            "both agree the player left after frame 1"
+        );
+
+        second.add_local_input(2)?;
+        let requests = advance_frame(second)?;
+        assert_eq!(
+            requests.last(),
+            Some(&Request::AdvanceFrame {
+                inputs: vec![
+                    PlayerInput::confirmed(2),
+                    PlayerInput::confirmed(2),
+                    PlayerInput::disconnected()
+                ]
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_player_keeps_playing() -> Result<(), SessionError> {
+        let (local_transport, remote_transport) =
+            LoopbackNetwork::new(NetworkConditions::default(), 0).connect()?;
+        let mut local = session(2, 0, vec![(1, local_transport)])?;
+        let mut remote = session(2, 1, vec![(0, remote_transport)])?;
+        synchronize(&mut local, &mut remote)?;
+
+        local.disconnect_player(1)?;
+        // well past the prediction window with nobody to confirm frames
+        for frame in 0..20 {
+            local.add_local_input(frame)?;
+            let requests = advance_frame(&mut local)?;
+            assert_eq!(
+                requests.last(),
+                Some(&advance(vec![
+                    PlayerInput::confirmed(frame),
+                    PlayerInput::disconnected()
+                ]))
+            );
+        }
+        assert_eq!(local.current_frame(), 20);
+        Ok(())
+    }
+
+    #[test]
+    fn test_desync_detected() -> Result<(), SessionError> {
+        let (local_transport, remote_transport) =
+            LoopbackNetwork::new(NetworkConditions::default(), 0).connect()?;
+        let mut local = session(2, 0, vec![(1, local_transport)])?;
+        let mut remote = session(2, 1, vec![(0, remote_transport)])?;
+        local.set_desync_detection(Some(1));
+        remote.set_desync_detection(Some(1));
+        synchronize(&mut local, &mut remote)?;
+        local.events();
+
+        // the remote's state is only the same as ours on frame 0
+        for frame in 0..4 {
+            for (session, multiplier) in vec![(&mut remote, 2), (&mut local, 1)] {
+                session.add_local_input(frame as u8)?;
+                for request in session.advance_frame()? {
+                    if let Request::SaveGameState { frame } = request {
+                        session.save_game_state(frame, frame, Some((frame * multiplier).into()));
+                    }
+                }
+            }
+        }
+        let mut events = Vec::new();
+        poll_until(|| {
+            local.poll()?;
+            remote.poll()?;
+            events.extend(local.events());
+            Ok(!events.is_empty())
+        })?;
+        assert_eq!(
+            events.first(),
+            Some(&SessionEvent::DesyncDetected {
+                player: 1,
+                frame: 1,
+                local: 1,
+                remote: 2
+            })
+        );
+        Ok(())
+    }
+
+    /// Local player in a match over the loopback network, the game state is
+    /// a hash of every input so far
+    struct LoopbackPlayer {
+        session: P2PSession<u8, u64>,
+        state: u64,
+        saved_states: HashMap<FrameSize, u64>,
+    }
+
+    impl LoopbackPlayer {
+        fn new(handle: PlayerHandle, transport: LoopbackTransport) -> Result<Self, SessionError> {
+            let mut session = P2PSession::new(8, 2, handle);
+            session.set_protocol_config(LoopbackPlayer::protocol_config());
+            session.add_remote_player(1 - handle, NetworkHandler::with_transport(transport))?;
+            session.start_recording(vec![]);
+            Ok(Self {
+                session,
+                state: 0,
+                saved_states: HashMap::new(),
+            })
+        }
+
+        /// Resend unacknowledged inputs and measure the round trip time every
+        /// few polls so lost packets are recovered quickly, sync requests are
+        /// reliable so they don't need retries
+        fn protocol_config() -> ProtocolConfig {
+            ProtocolConfig {
+                num_sync_packets: 1,
+                running_retry_interval: Duration::from_millis(10),
+                quality_report_interval: Duration::from_millis(10),
+                ..ProtocolConfig::default()
+            }
+        }
+
+        /// Play the next frame unless we are too far ahead of the peer
+        fn try_advance(&mut self, input: u8) -> Result<(), SessionError> {
+            match self.session.add_local_input(input) {
+                Err(SessionError::SyncError(SyncError::PredictionBarrierReached { .. }))
+                | Err(SessionError::FrameDelayPending) => return Ok(()),
+                result => result?,
+            }
+            for request in self.session.advance_frame()? {
+                match request {
+                    Request::SaveGameState { frame } => {
+                        self.saved_states.insert(frame, self.state);
+                        self.session.save_game_state(frame, self.state, None);
+                    }
+                    Request::LoadGameState { state, .. } => self.state = state,
+                    Request::AdvanceFrame { inputs } => {
+                        for (player, input) in inputs.into_iter().enumerate() {
+                            let input = input.input.unwrap_or(0) as u64;
+                            self.state = self.state.wrapping_mul(31) + input * (player as u64 + 1);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Run both players until they reach `frames`, each round of polls takes
+    /// a millisecond on the network's clock
+    fn run_players(
+        network: &LoopbackNetwork,
+        players: &mut [LoopbackPlayer],
+        frames: FrameSize,
+    ) -> Result<(), SessionError> {
+        for _ in 0..5000 {
+            for (handle, player) in players.iter_mut().enumerate() {
+                let frame = player.session.current_frame();
+                if player.session.is_running() && frame < frames {
+                    player.try_advance(frame as u8 * (handle as u8 + 1))?;
+                }
+                player.session.poll()?;
+            }
+            if players
+                .iter()
+                .all(|player| player.session.current_frame() == frames)
+            {
+                break;
+            }
+            network.advance(Duration::from_millis(1));
+        }
+        assert!(players
+            .iter()
+            .all(|player| player.session.current_frame() == frames));
+        Ok(())
+    }
+
+    /// Every frame both confirmed was simulated with the same inputs and
+    /// ended in the same state, returns how many frames were confirmed
+    fn assert_players_agree(players: &mut [LoopbackPlayer]) -> FrameSize {
+        let replays: Vec<_> = players
+            .iter_mut()
+            .map(|player| player.session.take_replay().unwrap())
+            .collect();
+        let confirmed = min(replays[0].len(), replays[1].len());
+        assert_eq!(
+            replays[0].inputs[..confirmed as usize],
+            replays[1].inputs[..confirmed as usize]
+        );
+        // frames are confirmed while polling so the state after the last
+        // confirmed frame may not have been resimulated yet
+        for frame in 0..confirmed {
+            assert_eq!(
+                players[0].saved_states.get(&frame),
+                players[1].saved_states.get(&frame),
+                "state for frame {}",
+                frame
+            );
+        }
+        confirmed
+    }
+
+    #[test]
+    fn test_bad_network() -> Result<(), SessionError> {
+        const FRAMES: FrameSize = 40;
+        let network = LoopbackNetwork::new(
+            NetworkConditions {
+                delay: Duration::from_millis(5),
+                jitter: Duration::from_millis(5),
+                packet_loss: 0.2,
+                duplicate_chance: 0.1,
+                reorder_chance: 0.1,
+            },
+            42,
+        );
+        let (first, second) = network.connect()?;
+        let mut players = vec![
+            LoopbackPlayer::new(0, first)?,
+            LoopbackPlayer::new(1, second)?,
+        ];
+        run_players(&network, &mut players, FRAMES)?;
+
+        // even after mispredictions and lost packets
+        assert!(assert_players_agree(&mut players) > FRAMES - 8);
+        Ok(())
+    }
+
+    fn adaptive_config() -> FrameDelayConfig {
+        FrameDelayConfig {
+            interval: 10,
+            frame_duration: Duration::from_millis(10),
+            ..FrameDelayConfig::default()
+        }
+    }
+
+    /// Players whose inputs take 20 polls to arrive so nearly every frame
+    /// rolls back, the 40ms round trip is worth 2 frames of delay
+    fn delayed_players() -> Result<(LoopbackNetwork, Vec<LoopbackPlayer>), SessionError> {
+        let network = LoopbackNetwork::new(
+            NetworkConditions {
+                delay: Duration::from_millis(20),
+                ..NetworkConditions::default()
+            },
+            0,
+        );
+        let (first, second) = network.connect()?;
+        let players = vec![
+            LoopbackPlayer::new(0, first)?,
+            LoopbackPlayer::new(1, second)?,
+        ];
+        Ok((network, players))
+    }
+
+    /// Frame and delay of every change to the player's frame delay
+    fn frame_delay_changes(player: &mut LoopbackPlayer) -> Vec<(FrameSize, FrameSize)> {
+        player
+            .session
+            .events()
+            .into_iter()
+            .filter_map(|event| match event {
+                SessionEvent::FrameDelayChanged { frame, delay } => Some((frame, delay)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_adaptive_frame_delay() -> Result<(), SessionError> {
+        const FRAMES: FrameSize = 80;
+        let (network, mut players) = delayed_players()?;
+        for player in players.iter_mut() {
+            player
+                .session
+                .set_adaptive_frame_delay(Some(adaptive_config()));
+        }
+        run_players(&network, &mut players, FRAMES)?;
+
+        // both raised the delay together on the same frames
+        let delay = players[0].session.frame_delay();
+        assert!(delay >= 2);
+        assert_eq!(players[1].session.frame_delay(), delay);
+        let changes = frame_delay_changes(&mut players[0]);
+        assert!(!changes.is_empty());
+        assert_eq!(frame_delay_changes(&mut players[1]), changes);
+        // each change starts an interval after the round it was proposed in
+        for (frame, _) in changes.iter() {
+            assert!(
+                *frame >= 20 && frame % 10 == 0,
+                "switched on frame {}",
+                frame
+            );
+        }
+
+        // the frames skipped by raising the delay reached the peer
+        assert!(assert_players_agree(&mut players) > FRAMES - 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_adaptive_frame_delay_mid_match() -> Result<(), SessionError> {
+        const FRAMES: FrameSize = 80;
+        let (network, mut players) = delayed_players()?;
+        run_players(&network, &mut players, 15)?;
+        for player in players.iter_mut() {
+            player
+                .session
+                .set_adaptive_frame_delay(Some(adaptive_config()));
+        }
+        run_players(&network, &mut players, FRAMES)?;
+
+        // the first round is 20 so the first change can only be on 30
+        let changes = frame_delay_changes(&mut players[0]);
+        assert!(!changes.is_empty());
+        assert_eq!(frame_delay_changes(&mut players[1]), changes);
+        assert!(changes
+            .iter()
+            .all(|(frame, _)| *frame >= 30 && frame % 10 == 0));
+        assert!(assert_players_agree(&mut players) > FRAMES - 10);
+        Ok(())
+    }
+
+    #[test]
+    fn test_adaptive_frame_delay_one_peer() -> Result<(), SessionError> {
+        const FRAMES: FrameSize = 80;
+        let (network, mut players) = delayed_players()?;
+        players[0]
+            .session
+            .set_adaptive_frame_delay(Some(adaptive_config()));
+        run_players(&network, &mut players, FRAMES)?;
+
+        // the second player answers every round with its fixed delay
+        let changes = frame_delay_changes(&mut players[0]);
+        assert!(!changes.is_empty());
+        assert!(changes.iter().all(|(frame, _)| frame % 10 == 0));
+        assert!(players[0].session.frame_delay() > 0);
+        assert_eq!(players[1].session.frame_delay(), 0);
+        assert!(frame_delay_changes(&mut players[1]).is_empty());
+        assert!(assert_players_agree(&mut players) > FRAMES - 10);
+        Ok(())
+    }
+}
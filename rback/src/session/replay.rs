@@ -0,0 +1,104 @@
+use crate::{
+    replay::Replay, session::handle_requests, FrameSize, NetworkInput, Request, SyncCallBacks,
+};
+
+/// Plays back a recorded `Replay` one frame at a time
+///
+/// The inputs in a replay are all confirmed so like a `SpectatorSession`
+/// there is never anything to save or roll back, `advance_frame` only returns
+/// `Request::AdvanceFrame`.
+pub struct ReplaySession<T: NetworkInput> {
+    replay: Replay<T>,
+    frame_count: FrameSize,
+}
+
+impl<T: NetworkInput> ReplaySession<T> {
+    pub fn new(replay: Replay<T>) -> Self {
+        Self {
+            replay,
+            frame_count: 0,
+        }
+    }
+
+    /// Settings the replay was recorded with
+    pub fn replay(&self) -> &Replay<T> {
+        &self.replay
+    }
+
+    /// Frame that will be simulated by the next `advance_frame`
+    pub fn current_frame(&self) -> FrameSize {
+        self.frame_count
+    }
+
+    /// True once every recorded frame has been played
+    pub fn is_finished(&self) -> bool {
+        self.frame_count >= self.replay.len()
+    }
+
+    /// Advance by the next recorded frame, this is empty once the replay is
+    /// finished
+    pub fn advance_frame<S>(&mut self) -> Vec<Request<T, S>> {
+        match self.replay.inputs.get(self.frame_count as usize) {
+            Some(inputs) => {
+                self.frame_count += 1;
+                vec![Request::AdvanceFrame {
+                    inputs: inputs.clone(),
+                }]
+            }
+            None => vec![],
+        }
+    }
+
+    /// Same as `advance_frame` but the frame is advanced using the game's
+    /// callbacks instead of being returned
+    pub fn advance_frame_with_callbacks<C>(&mut self, callbacks: &mut C)
+    where
+        C: SyncCallBacks<Input = T>,
+    {
+        let requests = self.advance_frame();
+        // nothing to save during a replay
+        handle_requests(requests, callbacks, |_, _, _| {});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::SessionError,
+        network::loopback::{LoopbackNetwork, NetworkConditions},
+        session::test_util::{play_confirmed_frames, session, synchronize},
+        PlayerInput,
+    };
+
+    #[test]
+    fn test_record_and_replay() -> Result<(), SessionError> {
+        let (host_transport, remote_transport) =
+            LoopbackNetwork::new(NetworkConditions::default(), 0).connect()?;
+        let mut host = session(2, 0, vec![(1, host_transport)])?;
+        host.start_recording(vec![7]);
+        let mut remote = session(2, 1, vec![(0, remote_transport)])?;
+        synchronize(&mut host, &mut remote)?;
+        play_confirmed_frames(&mut host, &mut remote, 4)?;
+
+        let replay = host.take_replay().unwrap();
+        assert_eq!(replay.num_players, 2);
+        assert_eq!(replay.game_settings, vec![7]);
+        assert_eq!(replay.len(), 4);
+
+        let advance = |frame: u8| Request::AdvanceFrame {
+            inputs: vec![
+                PlayerInput::confirmed(frame),
+                PlayerInput::confirmed(10 + frame),
+            ],
+        };
+        let mut session = ReplaySession::new(replay);
+        for frame in 0..4 {
+            assert!(!session.is_finished());
+            assert_eq!(session.advance_frame::<()>(), vec![advance(frame)]);
+        }
+        assert!(session.is_finished());
+        assert_eq!(session.advance_frame::<()>(), vec![]);
+        Ok(())
+    }
+}
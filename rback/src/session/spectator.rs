@@ -0,0 +1,207 @@
+use crate::{
+    error::SessionError,
+    network::{
+        protocol::{PeerProtocol, ProtocolConfig, ProtocolEvent},
+        udp::NetworkHandler,
+    },
+    session::{handle_requests, peer_event, SessionEvent},
+    FrameSize, NetworkInput, PlayerHandle, PlayerInput, Request, SyncCallBacks,
+};
+use std::collections::VecDeque;
+
+/// Watches a match by simulating the confirmed inputs streamed from a host
+/// `P2PSession`, similar to GGPO's SpectatorBackend
+///
+/// Spectators never predict so there is nothing to roll back, `advance_frame`
+/// only returns `Request::AdvanceFrame`s and returns nothing while waiting on
+/// the host. When it falls too far behind it simulates several frames per
+/// call to catch up.
+pub struct SpectatorSession<T: NetworkInput> {
+    host: PlayerHandle,
+    protocol: PeerProtocol<Vec<PlayerInput<T>>>,
+    /// Confirmed inputs from the host that have not been simulated yet
+    inputs: VecDeque<Vec<PlayerInput<T>>>,
+    frame_count: FrameSize,
+    /// Start catching up once more than this many frames are waiting
+    max_frames_behind: FrameSize,
+    /// Frames simulated per call while catching up
+    catchup_speed: FrameSize,
+    running: bool,
+    events: VecDeque<SessionEvent>,
+}
+
+impl<T: NetworkInput> SpectatorSession<T> {
+    /// `host` is the host's player handle, used for the events about it
+    pub fn new(host: PlayerHandle, handler: NetworkHandler) -> Self {
+        Self {
+            host,
+            protocol: PeerProtocol::new(handler, ProtocolConfig::default()),
+            inputs: VecDeque::new(),
+            frame_count: 0,
+            max_frames_behind: 10,
+            catchup_speed: 2,
+            running: false,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn set_protocol_config(&mut self, config: ProtocolConfig) {
+        self.protocol.set_config(config);
+    }
+
+    /// Simulate `catchup_speed` frames per call whenever more than
+    /// `max_frames_behind` frames are waiting
+    pub fn set_catchup(&mut self, max_frames_behind: FrameSize, catchup_speed: FrameSize) {
+        self.max_frames_behind = max_frames_behind;
+        self.catchup_speed = catchup_speed.max(1);
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Frame that will be simulated by the next `advance_frame`
+    pub fn current_frame(&self) -> FrameSize {
+        self.frame_count
+    }
+
+    /// Number of frames received from the host that have not been simulated
+    pub fn frames_behind(&self) -> FrameSize {
+        self.inputs.len() as FrameSize
+    }
+
+    /// Send any queued messages and receive confirmed inputs from the host
+    pub fn poll(&mut self) -> Result<(), SessionError> {
+        for event in self.protocol.poll()? {
+            if let ProtocolEvent::Input(input) = event {
+                self.inputs.push_back(input.input);
+            } else if let Some(event) = peer_event(self.host, event) {
+                self.events.push_back(event);
+            }
+        }
+
+        if !self.running && self.protocol.is_running() {
+            self.running = true;
+            self.events.push_back(SessionEvent::Running);
+        }
+        Ok(())
+    }
+
+    /// Take every event that happened since the last call
+    pub fn events(&mut self) -> Vec<SessionEvent> {
+        self.events.drain(..).collect()
+    }
+
+    /// Advance by every frame that should be simulated this tick, this is
+    /// empty if no confirmed inputs have arrived yet
+    pub fn advance_frame<S>(&mut self) -> Result<Vec<Request<T, S>>, SessionError> {
+        self.poll()?;
+        if !self.running {
+            return Err(SessionError::NotSynchronized);
+        }
+        let num_frames = if self.frames_behind() > self.max_frames_behind {
+            self.catchup_speed
+        } else {
+            1
+        };
+
+        let mut requests = Vec::new();
+        for _ in 0..num_frames {
+            match self.inputs.pop_front() {
+                Some(inputs) => {
+                    requests.push(Request::AdvanceFrame { inputs });
+                    self.frame_count += 1;
+                }
+                None => break,
+            }
+        }
+        Ok(requests)
+    }
+
+    /// Same as `poll` but any events are passed to `callbacks.on_event`
+    pub fn poll_with_callbacks<C>(&mut self, callbacks: &mut C) -> Result<(), SessionError>
+    where
+        C: SyncCallBacks<Input = T>,
+    {
+        self.poll()?;
+        self.dispatch_events(callbacks);
+        Ok(())
+    }
+
+    /// Same as `advance_frame` but the frames are advanced using the game's
+    /// callbacks instead of being returned
+    pub fn advance_frame_with_callbacks<C>(&mut self, callbacks: &mut C) -> Result<(), SessionError>
+    where
+        C: SyncCallBacks<Input = T>,
+    {
+        let requests = self.advance_frame()?;
+        // spectators never save
+        handle_requests(requests, callbacks, |_, _, _| {});
+        self.dispatch_events(callbacks);
+        Ok(())
+    }
+
+    fn dispatch_events<C>(&mut self, callbacks: &mut C)
+    where
+        C: SyncCallBacks<Input = T>,
+    {
+        for event in self.events.drain(..) {
+            callbacks.on_event(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        network::loopback::{LoopbackNetwork, NetworkConditions},
+        session::test_util::{play_confirmed_frames, poll_until, protocol_config, session},
+    };
+
+    #[test]
+    fn test_spectate() -> Result<(), SessionError> {
+        let network = LoopbackNetwork::new(NetworkConditions::default(), 0);
+        let (host_to_remote, remote_to_host) = network.connect()?;
+        let (host_to_spectator, spectator_to_host) = network.connect()?;
+        let mut host = session(2, 0, vec![(1, host_to_remote)])?;
+        host.add_spectator(NetworkHandler::with_transport(host_to_spectator));
+        let mut remote = session(2, 1, vec![(0, remote_to_host)])?;
+        let mut spectator: SpectatorSession<u8> =
+            SpectatorSession::new(0, NetworkHandler::with_transport(spectator_to_host));
+        spectator.set_protocol_config(protocol_config());
+        spectator.set_catchup(2, 3);
+
+        poll_until(|| {
+            host.poll()?;
+            remote.poll()?;
+            spectator.poll()?;
+            Ok(host.is_running() && remote.is_running() && spectator.is_running())
+        })?;
+        assert_eq!(spectator.events().last(), Some(&SessionEvent::Running));
+        assert_eq!(spectator.advance_frame::<()>()?, vec![]);
+
+        play_confirmed_frames(&mut host, &mut remote, 4)?;
+        poll_until(|| {
+            host.poll()?;
+            spectator.poll()?;
+            Ok(spectator.frames_behind() == 4)
+        })?;
+        assert_eq!(spectator.frames_behind(), 4);
+
+        let advance = |frame: u8| Request::AdvanceFrame {
+            inputs: vec![
+                PlayerInput::confirmed(frame),
+                PlayerInput::confirmed(10 + frame),
+            ],
+        };
+        // too far behind so it catches up
+        assert_eq!(
+            spectator.advance_frame::<()>()?,
+            vec![advance(0), advance(1), advance(2)]
+        );
+        assert_eq!(spectator.advance_frame::<()>()?, vec![advance(3)]);
+        assert_eq!(spectator.current_frame(), 4);
+        Ok(())
+    }
+}
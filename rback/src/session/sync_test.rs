@@ -0,0 +1,210 @@
+use crate::{
+    error::SessionError, game_input_frame::ConfirmedInput, session::handle_requests, sync::Sync,
+    FrameSize, GameInput, PlayerHandle, Request, SyncCallBacks,
+};
+use std::collections::BTreeMap;
+
+/// Finds non-determinism in a game without a network, similar to GGPO's
+/// SyncTestBackend
+///
+/// Every player is local. Each frame the session rolls back `check_distance`
+/// frames and resimulates them, the checksums the game gives with
+/// `save_game_state` for the resimulated frames must match the ones from the
+/// first time they were simulated. Frames saved without a checksum are not
+/// checked.
+pub struct SyncTestSession<T: GameInput, S> {
+    sync: Sync<T, S>,
+    check_distance: FrameSize,
+    /// Checksum from the first time each recent frame was saved
+    checksums: BTreeMap<FrameSize, u64>,
+    /// Frames the game was asked to save by the last `advance_frame`
+    pending_saves: Vec<FrameSize>,
+}
+
+impl<T: GameInput, S: Clone> SyncTestSession<T, S> {
+    pub fn new(check_distance: FrameSize, num_players: u8) -> Self {
+        Self {
+            // inputs and states are kept for every frame we roll back over,
+            // the extra frame lets the first input in when that is none
+            sync: Sync::new(check_distance + 2, num_players),
+            check_distance,
+            checksums: BTreeMap::new(),
+            pending_saves: Vec::new(),
+        }
+    }
+
+    /// Frame that will be simulated by the next `advance_frame`
+    pub fn current_frame(&self) -> FrameSize {
+        self.sync.frame_count
+    }
+
+    /// Add a player's input for the current frame
+    pub fn add_local_input(&mut self, player: PlayerHandle, input: T) -> Result<(), SessionError> {
+        let frame = self.sync.frame_count;
+        self.sync
+            .add_local_input(player, ConfirmedInput::new(input, frame))?;
+        Ok(())
+    }
+
+    /// Called by the game with its state and checksum when handling a
+    /// `Request::SaveGameState`
+    pub fn save_game_state(&mut self, frame: FrameSize, state: S, checksum: Option<u64>) {
+        self.sync.save_game_state(frame, state, checksum);
+    }
+
+    /// Check the states saved since the last call and advance by one frame.
+    /// The requests roll back `check_distance` frames, resimulate them and
+    /// then advance the current frame.
+    ///
+    /// Returns `SessionError::MismatchedChecksum` for the first resimulated
+    /// frame whose checksum changed.
+    pub fn advance_frame(&mut self) -> Result<Vec<Request<T, S>>, SessionError> {
+        self.check_saved_states()?;
+
+        let frame = self.sync.frame_count;
+        if self.check_distance > 0 && frame >= self.check_distance {
+            self.sync.schedule_rollback(frame - self.check_distance);
+        }
+        let requests = self.sync.advance_frame()?;
+
+        // only the rollback window is needed from here on
+        let confirmed_frame = self.sync.frame_count.saturating_sub(self.check_distance);
+        self.sync.set_last_confirmed_frame(confirmed_frame);
+        self.checksums = self.checksums.split_off(&confirmed_frame);
+
+        self.pending_saves = requests
+            .iter()
+            .filter_map(|request| match request {
+                Request::SaveGameState { frame } => Some(*frame),
+                _ => None,
+            })
+            .collect();
+        Ok(requests)
+    }
+
+    /// Same as `advance_frame` but the requests are performed using the
+    /// game's callbacks instead of being returned
+    pub fn advance_frame_with_callbacks<C>(&mut self, callbacks: &mut C) -> Result<(), SessionError>
+    where
+        C: SyncCallBacks<Input = T, SavedState = S>,
+    {
+        let requests = self.advance_frame()?;
+        let sync = &mut self.sync;
+        handle_requests(requests, callbacks, |frame, state, checksum| {
+            sync.save_game_state(frame, state, checksum)
+        });
+        Ok(())
+    }
+
+    /// Record the checksum of frames saved for the first time and compare the
+    /// resimulated ones
+    fn check_saved_states(&mut self) -> Result<(), SessionError> {
+        for frame in self.pending_saves.drain(..) {
+            let checksum = match self.sync.checksum(frame) {
+                Some(checksum) => checksum,
+                None => continue,
+            };
+            match self.checksums.get(&frame) {
+                Some(original) if *original != checksum => {
+                    return Err(SessionError::MismatchedChecksum {
+                        frame,
+                        original: *original,
+                        resimulated: checksum,
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    self.checksums.insert(frame, checksum);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{session::SessionEvent, PlayerInput};
+
+    /// Sums every input, optionally also adding how many frames it has
+    /// simulated which changes when resimulating
+    #[derive(Default)]
+    struct Game {
+        sum: u64,
+        frames_simulated: u64,
+        nondeterministic: bool,
+    }
+
+    impl SyncCallBacks for Game {
+        type Input = u64;
+        type SavedState = u64;
+
+        fn save_game_state(&mut self, _frame: FrameSize) -> u64 {
+            self.sum
+        }
+
+        fn checksum(&mut self, saved_state: &u64, _frame: FrameSize) -> Option<u64> {
+            Some(*saved_state)
+        }
+
+        fn load_game_state(&mut self, saved_state: u64, _frame: FrameSize) {
+            self.sum = saved_state;
+        }
+
+        fn advance_frame(&mut self, inputs: Vec<PlayerInput<u64>>) {
+            self.frames_simulated += 1;
+            self.sum += inputs.into_iter().filter_map(|i| i.input).sum::<u64>();
+            if self.nondeterministic {
+                self.sum += self.frames_simulated;
+            }
+        }
+
+        fn on_event(&mut self, _event: SessionEvent) {}
+    }
+
+    fn run(game: &mut Game, check_distance: FrameSize, frames: u64) -> Result<(), SessionError> {
+        let mut session: SyncTestSession<u64, u64> = SyncTestSession::new(check_distance, 2);
+        for frame in 0..frames {
+            session.add_local_input(0, frame)?;
+            session.add_local_input(1, frame * 2)?;
+            session.advance_frame_with_callbacks(game)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_deterministic() -> Result<(), SessionError> {
+        let mut game = Game::default();
+        run(&mut game, 2, 20)?;
+        assert_eq!(game.sum, (0..20).map(|frame| frame * 3).sum::<u64>());
+        // every frame after the first two was simulated 3 times
+        assert_eq!(game.frames_simulated, 2 + 18 * 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_check_distance() -> Result<(), SessionError> {
+        let mut game = Game {
+            nondeterministic: true,
+            ..Game::default()
+        };
+        // nothing is resimulated so nothing can mismatch
+        run(&mut game, 0, 20)?;
+        assert_eq!(game.frames_simulated, 20);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nondeterministic() {
+        let mut game = Game {
+            nondeterministic: true,
+            ..Game::default()
+        };
+        // frame 1's state is the first one saved again after resimulating
+        assert!(matches!(
+            run(&mut game, 2, 20),
+            Err(SessionError::MismatchedChecksum { frame: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::{
+    error::SessionError,
+    network::{loopback::LoopbackTransport, protocol::ProtocolConfig, udp::NetworkHandler},
+    session::{p2p::P2PSession, SessionEvent},
+    FrameSize, PlayerHandle, Request,
+};
+
+/// Sync with a single round trip so tests start quickly
+pub(crate) fn protocol_config() -> ProtocolConfig {
+    ProtocolConfig {
+        num_sync_packets: 1,
+        ..ProtocolConfig::default()
+    }
+}
+
+/// Session for `handle` connected to each of the `peers`
+pub(crate) fn session(
+    num_players: u8,
+    handle: PlayerHandle,
+    peers: Vec<(PlayerHandle, LoopbackTransport)>,
+) -> Result<P2PSession<u8, FrameSize>, SessionError> {
+    let mut session = P2PSession::new(8, num_players, handle);
+    session.set_protocol_config(protocol_config());
+    for (peer, transport) in peers {
+        session.add_remote_player(peer, NetworkHandler::with_transport(transport))?;
+    }
+    Ok(session)
+}
+
+/// Advance the frame and handle the save requests like a game would, the
+/// saved state is the frame
+pub(crate) fn advance_frame(
+    session: &mut P2PSession<u8, FrameSize>,
+) -> Result<Vec<Request<u8, FrameSize>>, SessionError> {
+    let requests = session.advance_frame()?;
+    for request in requests.iter() {
+        if let Request::SaveGameState { frame } = request {
+            session.save_game_state(*frame, *frame, None);
+        }
+    }
+    Ok(requests)
+}
+
+/// Call `poll` until it returns true, giving up after 100 rounds
+pub(crate) fn poll_until<F>(mut poll: F) -> Result<(), SessionError>
+where
+    F: FnMut() -> Result<bool, SessionError>,
+{
+    for _ in 0..100 {
+        if poll()? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Poll both sessions until they are running, returning the events each
+/// one saw
+pub(crate) fn synchronize(
+    local: &mut P2PSession<u8, FrameSize>,
+    remote: &mut P2PSession<u8, FrameSize>,
+) -> Result<(Vec<SessionEvent>, Vec<SessionEvent>), SessionError> {
+    let mut local_events = Vec::new();
+    let mut remote_events = Vec::new();
+    poll_until(|| {
+        local.poll()?;
+        remote.poll()?;
+        local_events.extend(local.events());
+        remote_events.extend(remote.events());
+        Ok(local.is_running() && remote.is_running())
+    })?;
+    Ok((local_events, remote_events))
+}
+
+/// Play `frames` frames where the host's input is the frame and the remote's
+/// is 10 more. The remote's inputs reach the host before it advances so each
+/// frame is confirmed right away
+pub(crate) fn play_confirmed_frames(
+    host: &mut P2PSession<u8, FrameSize>,
+    remote: &mut P2PSession<u8, FrameSize>,
+    frames: u8,
+) -> Result<(), SessionError> {
+    for frame in 0..frames {
+        remote.add_local_input(10 + frame)?;
+        advance_frame(remote)?;
+        host.add_local_input(frame)?;
+        advance_frame(host)?;
+    }
+    Ok(())
+}
@@ -1,109 +1,135 @@
 use crate::{
-    error::SyncError, game_input_frame::GameInputFrame, input_queue::InputQueue, FrameIndex,
-    FrameSize, GameInput, RollbackState, SaveFrame,
+    error::SyncError, game_input_frame::ConfirmedInput, input_queue::InputQueue,
+    prediction::PredictionStrategy, saved_states::SavedStates, FrameIndex, FrameSize, GameInput,
+    PlayerInput, Request,
 };
-use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use std::{cmp::min, sync::Arc};
 // TODO: simplify errors to only be the errors that could be thrown in that func
 
-const NUM_PLAYERS: u8 = 2;
-pub struct Sync<T: GameInput> {
+/// Whether a player is still in the match and the last frame we have their
+/// input for. This is sent to the other peers so everyone agrees on when a
+/// player left, similar to GGPO's ConnectStatus
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ConnectionStatus {
+    pub disconnected: bool,
+    /// Once disconnected this never increases
+    pub last_frame: FrameIndex,
+}
+
+pub struct Sync<T: GameInput, S> {
     max_prediction_frames: FrameSize,
     pub(crate) frame_count: FrameSize,
     last_confirmed_frame: FrameIndex,
-    target_post_roll_back_frame: FrameIndex,
-    // first is local, second is remote
-    // TODO: maybe make this slice of fixed size or just a vec?
-    input_queues: (InputQueue<T>, InputQueue<T>),
-    saved_states: VecDeque<FrameSize>,
+    /// One queue per player, indexed by the player's handle
+    input_queues: Vec<InputQueue<T>>,
+    /// Indexed by handle like the queues
+    connection_status: Vec<ConnectionStatus>,
+    /// A player disconnected before frames we already simulated with
+    /// predictions for them so we need to roll back to this frame
+    pending_rollback: FrameIndex,
+    saved_states: SavedStates<S>,
 }
 
-impl<T: GameInput> Sync<T> {
-    pub fn new(max_prediction_frames: FrameSize) -> Self {
+impl<T: GameInput, S: Clone> Sync<T, S> {
+    pub fn new(max_prediction_frames: FrameSize, num_players: u8) -> Self {
         Self {
             max_prediction_frames,
             frame_count: 0,
             last_confirmed_frame: None,
-            input_queues: (InputQueue::new(), InputQueue::new()),
-            saved_states: VecDeque::new(),
-            target_post_roll_back_frame: None,
+            input_queues: (0..num_players).map(|_| InputQueue::new()).collect(),
+            connection_status: vec![ConnectionStatus::default(); num_players as usize],
+            pending_rollback: None,
+            saved_states: SavedStates::new(max_prediction_frames as usize + 2),
         }
     }
 
-    pub fn in_rollback(&self) -> bool {
-        self.target_post_roll_back_frame.is_some()
+    pub fn num_players(&self) -> u8 {
+        self.input_queues.len() as u8
     }
 
+    pub fn max_prediction_frames(&self) -> FrameSize {
+        self.max_prediction_frames
+    }
+
+    /// Confirmed frames will never be rolled back to so any inputs and saved
+    /// states before it are dropped
     pub fn set_last_confirmed_frame(&mut self, frame: FrameSize) {
         self.last_confirmed_frame = Some(frame);
+        self.saved_states.discard_before(frame);
         if frame > 0 {
-            self.input_queues.0.discard_confirmed_frames(frame - 1);
-            self.input_queues.1.discard_confirmed_frames(frame - 1);
+            for queue in self.input_queues.iter_mut() {
+                queue.discard_confirmed_frames(frame - 1);
+            }
         }
     }
 
-    pub fn save_current_frame(&mut self) -> SaveFrame {
-        self.saved_states.push_back(self.frame_count);
-        SaveFrame {
+    /// Called by the game with its state when handling a
+    /// `Request::SaveGameState`. The checksum is optional and is used to
+    /// detect desyncs
+    pub fn save_game_state(&mut self, frame: FrameSize, state: S, checksum: Option<u64>) {
+        self.saved_states.save(frame, state, checksum);
+    }
+
+    /// Checksum saved with the state for this frame
+    pub fn checksum(&self, frame: FrameSize) -> Option<u64> {
+        self.saved_states.get_checksum(frame)
+    }
+
+    fn save_current_frame(&self) -> Request<T, S> {
+        Request::SaveGameState {
             frame: self.frame_count,
         }
     }
 
-    fn load_frame(
-        &mut self,
-        frame: FrameSize,
-        num_steps: FrameSize,
-    ) -> Result<RollbackState, SyncError> {
-        // remove older frames from saved states
-        self.saved_states
-            .retain(|saved_frame: &FrameSize| *saved_frame >= frame);
-
-        match self.saved_states.pop_front() {
-            Some(saved_frame) if saved_frame == frame => {
-                self.frame_count = frame;
-                self.reset_prediction(self.frame_count)?;
-                Ok(RollbackState { frame, num_steps })
-            }
-            // TODO: could error if i suck at the queue
-            _ => Err(SyncError::StateNotFound(frame)),
-        }
+    fn load_frame(&mut self, frame: FrameSize) -> Result<Request<T, S>, SyncError> {
+        let state = self
+            .saved_states
+            .get(frame)
+            .cloned()
+            .ok_or(SyncError::StateNotFound(frame))?;
+        self.frame_count = frame;
+        self.reset_prediction(self.frame_count)?;
+        Ok(Request::LoadGameState { frame, state })
     }
 
     #[inline(always)]
-    fn get_queue_mut(&mut self, queue: u8) -> Result<&mut InputQueue<T>, SyncError> {
-        // TODO: right now we only hold 2 but ggpo does up to 4
-        // not sure if we need more for spectators
-        match queue {
-            0 => Ok(&mut self.input_queues.0),
-            1 => Ok(&mut self.input_queues.1),
-            _ => Err(SyncError::BadQueueHandle(queue)),
-        }
+    fn get_queue(&self, queue: u8) -> Result<&InputQueue<T>, SyncError> {
+        self.input_queues
+            .get(queue as usize)
+            .ok_or(SyncError::BadQueueHandle(queue))
     }
 
     #[inline(always)]
-    fn get_queue(&self, queue: u8) -> Result<&InputQueue<T>, SyncError> {
-        // TODO: right now we only hold 2 but ggpo does up to 4
-        // not sure if we need more for spectators
-        match queue {
-            0 => Ok(&self.input_queues.0),
-            1 => Ok(&self.input_queues.1),
-            _ => Err(SyncError::BadQueueHandle(queue)),
-        }
+    fn get_queue_mut(&mut self, queue: u8) -> Result<&mut InputQueue<T>, SyncError> {
+        self.input_queues
+            .get_mut(queue as usize)
+            .ok_or(SyncError::BadQueueHandle(queue))
     }
 
+    /// Returns the input with the frame it was added for after the frame
+    /// delay, None if it was not added
     fn add_input(
         &mut self,
         queue: u8,
-        input: GameInputFrame<T>,
-    ) -> Result<GameInputFrame<T>, SyncError> {
-        let queue = self.get_queue_mut(queue)?;
-        queue.add_input(input).map_err(SyncError::from)
+        input: ConfirmedInput<T>,
+    ) -> Result<Option<ConfirmedInput<T>>, SyncError> {
+        let added = self.get_queue_mut(queue)?.add_input(input)?;
+        if let Some(added) = &added {
+            self.connection_status[queue as usize].last_frame = Some(added.frame);
+        }
+        Ok(added)
     }
 
     pub fn add_remote_input(
         &mut self,
         queue: u8,
-        input: GameInputFrame<T>,
-    ) -> Result<GameInputFrame<T>, SyncError> {
+        input: ConfirmedInput<T>,
+    ) -> Result<Option<ConfirmedInput<T>>, SyncError> {
+        // the frame they left on is frozen so anything after it is ignored
+        if self.is_disconnected(queue) {
+            return Ok(None);
+        }
         // TODO: should it only be queue == 1?
         self.add_input(queue, input)
     }
@@ -111,35 +137,71 @@ impl<T: GameInput> Sync<T> {
     pub fn add_local_input(
         &mut self,
         queue: u8,
-        input: GameInputFrame<T>,
-    ) -> Result<GameInputFrame<T>, SyncError> {
-        if let Some(last_confirmed_frame) = self.last_confirmed_frame {
-            let frames_behind = self.frame_count - last_confirmed_frame;
-            if frames_behind >= self.max_prediction_frames {
-                return Err(SyncError::PredictionBarrierReached {
-                    frames_behind,
-                    max_prediction_frames: self.max_prediction_frames,
-                });
-            }
-        }
-
-        // TODO: ggpo has this but not sure why yet
-        // TODO: could this be called twice if 2 queues add input before
-        // incrementating? It should really happen but maybe check if q = 0 so it only
-        // does it for local?
-        if self.frame_count == 0 {
-            // self.save_current_frame()
-            // TODO: require they save frame 0 before calling this?
-            // Or return option of SaveFrame telling them to save?
+        input: ConfirmedInput<T>,
+    ) -> Result<Option<ConfirmedInput<T>>, SyncError> {
+        // nothing confirmed is the same as confirming the frame before 0
+        let frames_behind = match self.last_confirmed_frame {
+            Some(last_confirmed_frame) => self.frame_count - last_confirmed_frame,
+            None => self.frame_count + 1,
+        };
+        // past this we could need to roll back to a state that was dropped
+        if frames_behind >= self.max_prediction_frames {
+            return Err(SyncError::PredictionBarrierReached {
+                frames_behind,
+                max_prediction_frames: self.max_prediction_frames,
+            });
         }
 
         // TODO: should it only be queue == 0?
         self.add_input(queue, input)
     }
 
-    pub fn increment_frame(&mut self) -> SaveFrame {
-        self.frame_count += 1;
-        self.save_current_frame()
+    /// Stop predicting inputs for a player that left after `last_frame`, they
+    /// will be given as disconnected for every later frame. If we already
+    /// simulated past that frame the next `advance_frame` will roll back.
+    ///
+    /// If the player was already disconnected the earlier frame is used so
+    /// every peer ends up agreeing on the same frame.
+    pub fn disconnect_player(
+        &mut self,
+        queue: u8,
+        last_frame: FrameIndex,
+    ) -> Result<(), SyncError> {
+        let status = self
+            .connection_status
+            .get_mut(queue as usize)
+            .ok_or(SyncError::BadQueueHandle(queue))?;
+        if status.disconnected && status.last_frame <= last_frame {
+            return Ok(());
+        }
+        status.disconnected = true;
+        status.last_frame = last_frame;
+
+        let first_disconnected_frame = last_frame.map_or(0, |frame| frame + 1);
+        if first_disconnected_frame < self.frame_count {
+            self.schedule_rollback(first_disconnected_frame);
+        }
+        Ok(())
+    }
+
+    /// Roll back to this frame on the next `advance_frame` even if nothing
+    /// was mispredicted, used by the sync test
+    pub(crate) fn schedule_rollback(&mut self, frame: FrameSize) {
+        self.pending_rollback = Some(
+            self.pending_rollback
+                .map_or(frame, |pending| min(pending, frame)),
+        );
+    }
+
+    pub fn is_disconnected(&self, queue: u8) -> bool {
+        self.connection_status
+            .get(queue as usize)
+            .map_or(false, |status| status.disconnected)
+    }
+
+    /// Status of every player indexed by handle
+    pub fn connection_status(&self) -> &[ConnectionStatus] {
+        &self.connection_status
     }
 
     pub fn set_frame_delay(&mut self, queue: u8, delay: FrameSize) -> Result<(), SyncError> {
@@ -147,120 +209,133 @@ impl<T: GameInput> Sync<T> {
         Ok(())
     }
 
+    /// Use this strategy to predict every player's missing inputs
+    pub fn set_prediction_strategy(&mut self, strategy: Arc<dyn PredictionStrategy<T>>) {
+        for queue in self.input_queues.iter_mut() {
+            queue.set_prediction_strategy(strategy.clone());
+        }
+    }
+
     fn reset_prediction(&mut self, frame: FrameSize) -> Result<(), SyncError> {
-        self.input_queues.0.reset_prediction(frame)?;
-        self.input_queues.1.reset_prediction(frame)?;
+        for queue in self.input_queues.iter_mut() {
+            queue.reset_prediction(frame)?;
+        }
         Ok(())
     }
 
-    fn check_simulation_consistency(&self) -> FrameIndex {
-        // TODO: cleanup seems really gross
-        let mut first_incorrect_frame = None;
-        for i in 0..NUM_PLAYERS {
-            let q = self.get_queue(i).expect("Should always be a valid queue");
-            match (q.first_incorrect_frame, first_incorrect_frame) {
-                (Some(q_frame), Some(sim_frame)) => {
-                    if q_frame < sim_frame {
-                        first_incorrect_frame = q.first_incorrect_frame;
-                    }
-                }
-                (Some(_), None) => {
-                    first_incorrect_frame = q.first_incorrect_frame;
-                }
-                _ => {}
-            }
-        }
-        first_incorrect_frame
+    /// Earliest frame the next `advance_frame` will roll back to, if any
+    pub(crate) fn check_simulation_consistency(&self) -> FrameIndex {
+        self.input_queues
+            .iter()
+            .filter_map(|q| q.first_incorrect_frame)
+            .chain(self.pending_rollback)
+            .min()
     }
 
-    pub fn check_simulation(&mut self) -> Result<Option<RollbackState>, SyncError> {
-        let seek_to = self.check_simulation_consistency();
-        match seek_to {
-            Some(seek_to) => Ok(Some(self.pre_roll_back(seek_to)?)),
-            None => Ok(None),
+    /// Called once per frame after the local inputs for the current frame
+    /// have been added. Returns the requests the game must perform in order.
+    ///
+    /// If a prediction was wrong this will first load the frame of the
+    /// earliest misprediction and resimulate up to the current frame, then
+    /// save and advance the current frame.
+    pub fn advance_frame(&mut self) -> Result<Vec<Request<T, S>>, SyncError> {
+        let mut requests = Vec::new();
+        if let Some(seek_to) = self.check_simulation_consistency() {
+            self.roll_back(seek_to, &mut requests)?;
+            self.pending_rollback = None;
         }
+        requests.push(self.save_current_frame());
+        self.simulate_frame(&mut requests)?;
+        Ok(requests)
     }
 
-    // pre_roll_back and post_roll_back map to AdjustSimulation in ggpo
-    pub fn pre_roll_back(&mut self, seek_to: FrameSize) -> Result<RollbackState, SyncError> {
-        let count = self.frame_count - seek_to;
-        self.target_post_roll_back_frame = Some(self.frame_count);
-
-        self.load_frame(seek_to, count)
-        // TODO: ggpo has assert here https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/sync.cpp#L156
-        // but i think load frame covers it
-    }
-
-    pub fn post_roll_back(&mut self) -> Result<(), SyncError> {
-        match self.target_post_roll_back_frame {
-            Some(frame_count) => {
-                if frame_count != self.frame_count {
-                    Err(SyncError::SimulationError {
-                        given: self.frame_count,
-                        expected: frame_count,
-                    })
-                } else {
-                    self.target_post_roll_back_frame = None;
-                    Ok(())
-                }
-            }
-            None => Err(SyncError::NotInRollback),
+    // maps to AdjustSimulation in ggpo
+    fn roll_back(
+        &mut self,
+        seek_to: FrameSize,
+        requests: &mut Vec<Request<T, S>>,
+    ) -> Result<(), SyncError> {
+        let target_frame = self.frame_count;
+        requests.push(self.load_frame(seek_to)?);
+
+        // the loaded frame is already saved so only save the frames after it
+        self.simulate_frame(requests)?;
+        while self.frame_count < target_frame {
+            requests.push(self.save_current_frame());
+            self.simulate_frame(requests)?;
         }
+        // https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/sync.cpp#L156
+        debug_assert_eq!(self.frame_count, target_frame);
+        Ok(())
+    }
+
+    fn simulate_frame(&mut self, requests: &mut Vec<Request<T, S>>) -> Result<(), SyncError> {
+        let inputs = self.synchronize_inputs()?;
+        requests.push(Request::AdvanceFrame { inputs });
+        self.frame_count += 1;
+        Ok(())
     }
 
     /// Called each frame by the game to get inputs for each player
     /// Returns Vec where each index corresponds to the input for that
-    /// queue/player
-    pub fn synchronize_inputs(&mut self) -> Result<Vec<Option<T>>, SyncError> {
-        let mut res = Vec::new();
+    /// queue/player, inputs we don't have yet are predicted
+    pub fn synchronize_inputs(&mut self) -> Result<Vec<PlayerInput<T>>, SyncError> {
+        let mut res = Vec::with_capacity(self.input_queues.len());
         let frame = self.frame_count;
-        for i in 0..NUM_PLAYERS {
-            let queue = self.get_queue_mut(i)?;
-            // TODO: check if player disconnected
-            res.push(queue.get_input(frame)?.input);
+        for (queue, status) in self.input_queues.iter_mut().zip(&self.connection_status) {
+            if is_disconnected_at(status, frame) {
+                res.push(PlayerInput::disconnected());
+            } else {
+                res.push(queue.get_input(frame)?);
+            }
         }
         Ok(res)
     }
 
-    // TODO: i think this is only called by spectators
+    /// A player's input for a frame as it was added to their queue
+    pub fn get_confirmed_input(
+        &self,
+        queue: u8,
+        frame: FrameSize,
+    ) -> Result<&ConfirmedInput<T>, SyncError> {
+        Ok(self.get_queue(queue)?.get_confirmed_input(frame)?)
+    }
+
+    /// Inputs for a frame every player has sent, used to feed spectators
     pub fn get_confirmed_inputs(
         &mut self,
         frame: FrameSize,
-    ) -> Result<Vec<GameInputFrame<T>>, SyncError> {
-        let mut res = Vec::new();
-        for i in 0..NUM_PLAYERS {
-            let queue = self.get_queue(i)?;
-            // TODO: check if player disconnected
-            res.push(queue.get_confirmed_input(frame)?);
+    ) -> Result<Vec<PlayerInput<T>>, SyncError> {
+        let mut res = Vec::with_capacity(self.input_queues.len());
+        for (queue, status) in self.input_queues.iter().zip(&self.connection_status) {
+            if is_disconnected_at(status, frame) {
+                res.push(PlayerInput::disconnected());
+            } else {
+                let confirmed = queue.get_confirmed_input(frame)?;
+                res.push(PlayerInput::confirmed(confirmed.input.clone()));
+            }
         }
         Ok(res)
     }
 }
 
+/// Inputs up to the last frame of a disconnected player are still real
+fn is_disconnected_at(status: &ConnectionStatus, frame: FrameSize) -> bool {
+    status.disconnected && status.last_frame.map_or(true, |last| frame > last)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_add() {
-        let mut sync: Sync<&str> = Sync::new(4);
+        let mut sync: Sync<&str, FrameSize> = Sync::new(4, 2);
         // first frame adds
         let added = sync.add_input(0, ("hi_0", 0).into()).unwrap();
-        assert_eq!(
-            added,
-            GameInputFrame {
-                frame: Some(0),
-                input: Some("hi_0"),
-            }
-        );
+        assert_eq!(added, Some(ConfirmedInput::new("hi_0", 0)));
         let added = sync.add_input(1, ("hi_1", 0).into()).unwrap();
-        assert_eq!(
-            added,
-            GameInputFrame {
-                frame: Some(0),
-                input: Some("hi_1"),
-            }
-        );
+        assert_eq!(added, Some(ConfirmedInput::new("hi_1", 0)));
 
         let err = sync.add_input(10, ("bad queue", 0).into()).err().unwrap();
         assert_eq!(err, SyncError::BadQueueHandle(10));
@@ -268,61 +343,75 @@ mod tests {
 
     #[test]
     fn test_add_local_input() {
-        let mut sync: Sync<&str> = Sync::new(4);
+        let mut sync: Sync<&str, FrameSize> = Sync::new(4, 2);
 
         let added = sync.add_local_input(0, ("hi_0", 0).into()).unwrap();
-        assert_eq!(
-            added,
-            GameInputFrame {
-                frame: Some(0),
-                input: Some("hi_0"),
-            }
-        );
+        assert_eq!(added, Some(ConfirmedInput::new("hi_0", 0)));
+    }
+
+    type TestRequest = Request<&'static str, FrameSize>;
+
+    fn save(frame: FrameSize) -> TestRequest {
+        Request::SaveGameState { frame }
+    }
+
+    // the tests use the frame number as the saved state
+    fn load(frame: FrameSize) -> TestRequest {
+        Request::LoadGameState {
+            frame,
+            state: frame,
+        }
     }
 
+    fn advance(inputs: Vec<PlayerInput<&'static str>>) -> TestRequest {
+        Request::AdvanceFrame { inputs }
+    }
+
+    fn confirmed(input: &'static str) -> PlayerInput<&'static str> {
+        PlayerInput::confirmed(input)
+    }
+
+    fn predicted(input: Option<&'static str>) -> PlayerInput<&'static str> {
+        PlayerInput::predicted(input)
+    }
+
+    /// Advance the frame and handle the save requests like a game would
     fn advance_frame(
-        sync: &mut Sync<&str>,
-        expected_frame: FrameSize,
-        expected_check_simulation_res: Option<RollbackState>,
-    ) -> Result<(), SyncError> {
-        assert_eq!(
-            sync.increment_frame(),
-            SaveFrame {
-                frame: expected_frame
+        sync: &mut Sync<&'static str, FrameSize>,
+    ) -> Result<Vec<TestRequest>, SyncError> {
+        let requests = sync.advance_frame()?;
+        for request in requests.iter() {
+            if let Request::SaveGameState { frame } = request {
+                sync.save_game_state(*frame, *frame, None);
             }
-        );
-        assert_eq!(sync.check_simulation()?, expected_check_simulation_res);
-        Ok(())
+        }
+        Ok(requests)
     }
 
     #[test]
-    fn test_check_simulation() -> Result<(), SyncError> {
-        let mut sync: Sync<&str> = Sync::new(4);
-
-        // TODO: for now we require they call save state before doing anything
-        assert_eq!(sync.save_current_frame(), SaveFrame { frame: 0 });
+    fn test_advance_frame() -> Result<(), SyncError> {
+        let mut sync: Sync<&str, FrameSize> = Sync::new(4, 2);
 
         // add local inputs but don't add remote to simulate a delay
         sync.add_local_input(0, ("first", 0).into())?;
-
         assert_eq!(
-            sync.synchronize_inputs()?,
+            advance_frame(&mut sync)?,
             vec![
-                Some("first"),
+                save(0),
                 // second queue has nothing to predict from so it will return null input
-                None
+                advance(vec![confirmed("first"), predicted(None)])
             ]
         );
-        // simulate game state going forward without getting remote input
-        advance_frame(&mut sync, 1, None)?;
 
-        // simulate a few more frames, then get the inputs for the first
+        // simulate a frame without getting remote input
         sync.add_local_input(0, ("second", 1).into())?;
-        assert_eq!(sync.synchronize_inputs()?, vec![Some("second"), None]);
-        advance_frame(&mut sync, 2, None)?;
+        assert_eq!(
+            advance_frame(&mut sync)?,
+            vec![save(1), advance(vec![confirmed("second"), predicted(None)])]
+        );
 
         // we got inputs for frame 0 on the start of frame 2 so we should roll back to
-        // the start of frame 2
+        // frame 0 and resimulate before advancing frame 2
         sync.add_local_input(0, ("third", 2).into())?;
         sync.add_remote_input(1, ("remote_1", 0).into())?;
 
@@ -332,81 +421,164 @@ mod tests {
             SyncError::QueueError(crate::error::InputQueueError::GetDurningPrediction)
         );
 
-        // This would be called every frame with increment_frame
         assert_eq!(
-            sync.check_simulation()?,
-            Some(RollbackState {
-                frame: 0,
-                num_steps: 2,
-            },)
+            advance_frame(&mut sync)?,
+            vec![
+                load(0),
+                // should get remote input now and use old local input
+                advance(vec![confirmed("first"), confirmed("remote_1")]),
+                save(1),
+                // does not yet have the next input so it should predict with the last remote
+                advance(vec![confirmed("second"), predicted(Some("remote_1"))]),
+                save(2),
+                advance(vec![confirmed("third"), predicted(Some("remote_1"))]),
+            ]
         );
+        // the session confirms frames after they are rolled back
+        sync.set_last_confirmed_frame(0);
+
+        // we get inputs for frame 1 on the start of frame 3 so roll back to here
+        sync.add_local_input(0, ("fourth", 3).into())?;
+        sync.add_remote_input(1, ("remote_2", 1).into())?;
 
-        // If they don't rollback error
         assert_eq!(
-            sync.post_roll_back().err().unwrap(),
-            SyncError::SimulationError {
-                given: 0,
-                expected: 2,
-            }
+            advance_frame(&mut sync)?,
+            vec![
+                load(1),
+                advance(vec![confirmed("second"), confirmed("remote_2")]),
+                save(2),
+                advance(vec![confirmed("third"), predicted(Some("remote_2"))]),
+                save(3),
+                advance(vec![confirmed("fourth"), predicted(Some("remote_2"))]),
+            ]
         );
+        sync.set_last_confirmed_frame(1);
 
-        // should get remote input now and use old local input
+        // correct prediction so no rollback
+        sync.add_local_input(0, ("fifth", 4).into())?;
+        sync.add_remote_input(1, ("remote_2", 2).into())?;
         assert_eq!(
-            sync.synchronize_inputs()?,
-            vec![Some("first"), Some("remote_1")]
+            advance_frame(&mut sync)?,
+            vec![
+                save(4),
+                advance(vec![confirmed("fifth"), predicted(Some("remote_2"))])
+            ]
         );
-        advance_frame(&mut sync, 1, None)?;
 
-        // not enough rollback
+        Ok(())
+    }
+
+    #[test]
+    fn test_prediction_barrier() -> Result<(), SyncError> {
+        let mut sync: Sync<&str, FrameSize> = Sync::new(2, 2);
+
+        sync.add_local_input(0, ("first", 0).into())?;
+        advance_frame(&mut sync)?;
+
+        // nothing is confirmed so we can't get any further ahead
         assert_eq!(
-            sync.post_roll_back().err().unwrap(),
-            SyncError::SimulationError {
-                given: 1,
-                expected: 2,
+            sync.add_local_input(0, ("second", 1).into()).err().unwrap(),
+            SyncError::PredictionBarrierReached {
+                frames_behind: 2,
+                max_prediction_frames: 2,
             }
         );
 
-        // does not yet have the next input so it should predict with the last remote
+        sync.add_remote_input(1, ("remote", 0).into())?;
+        sync.set_last_confirmed_frame(0);
+        sync.add_local_input(0, ("second", 1).into())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_many_players() -> Result<(), SyncError> {
+        let mut sync: Sync<&str, FrameSize> = Sync::new(4, 4);
+
+        sync.add_local_input(0, ("local", 0).into())?;
+        sync.add_remote_input(2, ("remote_2", 0).into())?;
         assert_eq!(
-            sync.synchronize_inputs()?,
-            vec![Some("second"), Some("remote_1")]
+            advance_frame(&mut sync)?,
+            vec![
+                save(0),
+                advance(vec![
+                    confirmed("local"),
+                    predicted(None),
+                    confirmed("remote_2"),
+                    predicted(None),
+                ])
+            ]
         );
-        advance_frame(&mut sync, 2, None)?;
 
-        // Correctly rolled back so no error
-        sync.post_roll_back()?;
+        // late input for the last player should cause a rollback
+        sync.add_local_input(0, ("local", 1).into())?;
+        sync.add_remote_input(3, ("remote_3", 0).into())?;
+        assert_eq!(
+            advance_frame(&mut sync)?,
+            vec![
+                load(0),
+                advance(vec![
+                    confirmed("local"),
+                    predicted(None),
+                    confirmed("remote_2"),
+                    confirmed("remote_3"),
+                ]),
+                save(1),
+                advance(vec![
+                    confirmed("local"),
+                    predicted(None),
+                    predicted(Some("remote_2")),
+                    predicted(Some("remote_3")),
+                ]),
+            ]
+        );
 
-        // now play the frame as normal
-        advance_frame(&mut sync, 3, None)?;
+        let err = sync.add_input(4, ("bad queue", 0).into()).err().unwrap();
+        assert_eq!(err, SyncError::BadQueueHandle(4));
+        Ok(())
+    }
 
-        // we get inputs for frame 1 on the start of frame 3 so roll back to here
-        sync.add_local_input(0, ("fourth", 3).into())?;
-        sync.add_remote_input(1, ("remote_2", 1).into())?;
+    #[test]
+    fn test_disconnected() -> Result<(), SyncError> {
+        let mut sync: Sync<&str, FrameSize> = Sync::new(4, 2);
 
-        // This would be called every frame with increment_frame
+        sync.add_local_input(0, ("local", 0).into())?;
+        sync.add_remote_input(1, ("remote", 0).into())?;
+        advance_frame(&mut sync)?;
+        sync.add_local_input(0, ("local", 1).into())?;
         assert_eq!(
-            sync.check_simulation()?,
-            Some(RollbackState {
-                frame: 1,
-                num_steps: 2,
-            },)
+            advance_frame(&mut sync)?,
+            vec![
+                save(1),
+                advance(vec![confirmed("local"), predicted(Some("remote"))])
+            ]
         );
 
+        // frame 1 was simulated with a prediction for the remote so it has to
+        // be redone without them
+        sync.disconnect_player(1, Some(0))?;
+        assert!(sync.is_disconnected(1));
+        sync.add_remote_input(1, ("late", 1).into())?;
+        sync.add_local_input(0, ("local", 2).into())?;
+        let disconnected = || advance(vec![confirmed("local"), PlayerInput::disconnected()]);
         assert_eq!(
-            sync.synchronize_inputs()?,
-            vec![Some("second"), Some("remote_2")]
+            advance_frame(&mut sync)?,
+            vec![load(1), disconnected(), save(2), disconnected()]
         );
-        advance_frame(&mut sync, 2, None)?;
-
         assert_eq!(
-            sync.synchronize_inputs()?,
-            vec![Some("third"), Some("remote_2")]
+            sync.connection_status()[1],
+            ConnectionStatus {
+                disconnected: true,
+                last_frame: Some(0)
+            }
         );
-        advance_frame(&mut sync, 3, None)?;
-
-        // Correctly rolled back so no error
-        sync.post_roll_back()?;
 
+        // a later disconnect frame is ignored
+        sync.disconnect_player(1, Some(1))?;
+        assert_eq!(sync.connection_status()[1].last_frame, Some(0));
+        assert_eq!(
+            sync.disconnect_player(2, None),
+            Err(SyncError::BadQueueHandle(2))
+        );
         Ok(())
     }
 }
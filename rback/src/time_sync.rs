@@ -0,0 +1,94 @@
+use crate::FrameSize;
+
+// https://github.com/pond3r/ggpo/blob/7ddadef8546a7d99ff0b3530c6056bc8ee4b9c0a/src/lib/ggpo/timesync.h#L13
+const FRAME_WINDOW_SIZE: usize = 40;
+const MIN_FRAME_ADVANTAGE: i32 = 3;
+const MAX_FRAME_ADVANTAGE: i32 = 9;
+
+/// Tracks how far ahead of a peer we are so the faster player can be told to
+/// wait, similar to GGPO's TimeSync
+///
+/// Advantage is how many frames the other side is ahead, so if ours is lower
+/// than the peer's we are running ahead of them.
+pub struct TimeSync {
+    local: [i32; FRAME_WINDOW_SIZE],
+    remote: [i32; FRAME_WINDOW_SIZE],
+}
+
+impl Default for TimeSync {
+    fn default() -> Self {
+        Self {
+            local: [0; FRAME_WINDOW_SIZE],
+            remote: [0; FRAME_WINDOW_SIZE],
+        }
+    }
+}
+
+impl TimeSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the advantages when sending the input for this frame
+    pub fn advance_frame(&mut self, frame: FrameSize, local_advantage: i32, remote_advantage: i32) {
+        let idx = frame as usize % FRAME_WINDOW_SIZE;
+        self.local[idx] = local_advantage;
+        self.remote[idx] = remote_advantage;
+    }
+
+    /// Number of frames we should wait for the peer to catch up, averaged
+    /// over the last `FRAME_WINDOW_SIZE` frames
+    pub fn recommend_frame_wait_duration(&self) -> FrameSize {
+        let advantage = average(&self.local);
+        let remote_advantage = average(&self.remote);
+        // they are ahead of us so they should be the one waiting
+        if advantage >= remote_advantage {
+            return 0;
+        }
+        // both of us should move half the gap so we only wait for half
+        let sleep_frames = ((remote_advantage - advantage) / 2.0).round() as i32;
+        if sleep_frames < MIN_FRAME_ADVANTAGE {
+            return 0;
+        }
+        sleep_frames.min(MAX_FRAME_ADVANTAGE) as FrameSize
+    }
+}
+
+fn average(values: &[i32]) -> f32 {
+    values.iter().sum::<i32>() as f32 / values.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time_sync_with(local_advantage: i32, remote_advantage: i32) -> TimeSync {
+        let mut time_sync = TimeSync::new();
+        for frame in 0..FRAME_WINDOW_SIZE as FrameSize {
+            time_sync.advance_frame(frame, local_advantage, remote_advantage);
+        }
+        time_sync
+    }
+
+    #[test]
+    fn test_recommend_frame_wait_duration() {
+        assert_eq!(TimeSync::new().recommend_frame_wait_duration(), 0);
+        // we are behind so the peer waits
+        assert_eq!(time_sync_with(4, -4).recommend_frame_wait_duration(), 0);
+        // ahead but not by enough to bother
+        assert_eq!(time_sync_with(-2, 2).recommend_frame_wait_duration(), 0);
+        assert_eq!(time_sync_with(-4, 4).recommend_frame_wait_duration(), 4);
+        assert_eq!(
+            time_sync_with(-20, 20).recommend_frame_wait_duration(),
+            MAX_FRAME_ADVANTAGE as FrameSize
+        );
+    }
+
+    #[test]
+    fn test_averages_window() {
+        let mut time_sync = time_sync_with(-4, 4);
+        // a single spike does not move the average much
+        time_sync.advance_frame(FRAME_WINDOW_SIZE as FrameSize, -40, 40);
+        assert_eq!(time_sync.recommend_frame_wait_duration(), 5);
+    }
+}